@@ -7,13 +7,28 @@ A WIP just-in-time compiler for the GameBoy and GameBoy Colour.
 
 Currently just disassembles a given binary.
 "#)]
+#[structopt(after_help = r#"KEYS:
+    Arrow keys      Joypad directions
+    X, Z            A, B
+    Backspace       Select
+    Enter           Start
+    N               Advance a frame, with --wait
+    0-9             Pick a save state slot
+    F5, F8          Save to and load from the slot
+    R               Rewind while held
+"#)]
 pub struct Args {
-    /// GB bios file
-    pub bios: String,
+    /// GB bios file.  Without one, the cartridge is started in the state the bios leaves behind
+    #[structopt(short, long)]
+    pub bios: Option<String>,
 
-    /// GB rom to run
+    /// GB rom to run, or a GBS file to play in headless mode
     pub rom: String,
 
+    /// Song to play from a GBS file, numbered from 1.  Defaults to the file's own default song.
+    #[structopt(long)]
+    pub track: Option<u8>,
+
     /// Logfile to write GB and x86 disassembly to
     #[structopt(short, long)]
     pub disassembly_logfile: Option<String>,
@@ -38,9 +53,72 @@ pub struct Args {
     #[structopt(short, long)]
     pub wait: bool,
 
+    /// Advance the cartridge real-time clock with the host's wall clock instead of emulated time
+    #[structopt(long)]
+    pub rtc_host_clock: bool,
+
+    /// File to load and store battery-backed cartridge RAM in, defaults to the ROM path with a
+    /// .sav extension
+    #[structopt(long)]
+    pub save_path: Option<String>,
+
+    /// Render dot by dot with a pixel FIFO, which gets mid-scanline effects and the length of
+    /// mode 3 right at the cost of speed
+    #[structopt(long)]
+    pub pixel_fifo: bool,
+
+    /// Seconds of play kept for rewinding, 0 turns it off
+    #[structopt(long, default_value = "10")]
+    pub rewind_seconds: f64,
+
+    /// Record the joypad to a movie file, starting from power-on or the state from --load-state.
+    /// Rewinding and loading states are turned off while recording.
+    #[structopt(long, conflicts_with = "rtc-host-clock")]
+    pub record_movie: Option<String>,
+
+    /// Play back a movie, from the state it was recorded from.  The GUI takes the joypad back
+    /// once it ends, headless mode stops.  Movies recorded with --verify are checked frame by
+    /// frame.
+    #[structopt(
+        long,
+        conflicts_with_all = &["rtc-host-clock", "record-movie", "load-state"]
+    )]
+    pub play_movie: Option<String>,
+
+    /// Store a hash of the machine's state after each frame of the recorded movie, so playback
+    /// can report the first frame that comes out differently
+    #[structopt(long, requires = "record-movie")]
+    pub verify: bool,
+
     /// Whether to run in headless mode, where the gb is emulated with no IO, just to generate logs
     #[structopt(short = "H", long)]
     pub headless: bool,
+
+    /// Load the save state in this slot before starting.  States are kept next to the ROM with
+    /// the slot in the extension, like .ss1 for slot 1.
+    #[structopt(long)]
+    pub load_state: Option<u8>,
+
+    /// Save the state to this slot once the last frame has run, in headless mode
+    #[structopt(long, requires = "frames")]
+    pub save_state: Option<u8>,
+
+    /// Stop after running this many frames, in headless mode
+    #[structopt(long)]
+    pub frames: Option<u64>,
+
+    /// Record the audio to a WAV file, in headless mode
+    #[structopt(long)]
+    pub wav: Option<String>,
+
+    /// Sample rate of the recorded audio, from 8000 to 192000
+    #[structopt(long, default_value = "48000", parse(try_from_str = parse_wav_rate))]
+    pub wav_rate: u32,
+
+    /// Also record each channel to its own WAV file, named after the main one with .ch1.wav to
+    /// .ch4.wav in place of its extension
+    #[structopt(long, requires = "wav")]
+    pub wav_stems: bool,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -68,3 +146,21 @@ fn parse_tuple(src: &str) -> Result<(u32, u32), DimensionParseError> {
         _ => Err(src.into()),
     }
 }
+
+#[derive(thiserror::Error, Debug)]
+enum WavRateParseError {
+    #[error("Failed to parse {0}")]
+    NotANumber(String),
+    #[error("Sample rate {0} is outside of 8000 to 192000")]
+    OutOfRange(u32),
+}
+
+fn parse_wav_rate(src: &str) -> Result<u32, WavRateParseError> {
+    let rate = src
+        .parse()
+        .map_err(|_| WavRateParseError::NotANumber(src.into()))?;
+    match rate {
+        8000..=192000 => Ok(rate),
+        _ => Err(WavRateParseError::OutOfRange(rate)),
+    }
+}
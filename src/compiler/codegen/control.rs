@@ -12,10 +12,17 @@ pub(super) fn generate(
     use ControlCommand::*;
     match cmd {
         Nop => {}
-        Halt | Stop => {
+        Halt => {
+            dynasm!(ops
+                ; mov r8, [rsp + 0x08]
+                ; mov BYTE [r8 + 0x0d], 1 // halted
+            );
+            return EpilogueDescription::Exit;
+        }
+        Stop => {
             dynasm!(ops
                 ;; push_state(ops)
-                ; mov rax, QWORD log_halt as _
+                ; mov rax, QWORD log_stop as _
                 ; mov di, r13w
                 ; call rax
                 ;; pop_state(ops)
@@ -44,6 +51,6 @@ pub(super) fn generate(
     Default::default()
 }
 
-extern "sysv64" fn log_halt(pc: u16) {
-    warn!("Executing halt/stop at {:#06x?}", pc);
+extern "sysv64" fn log_stop(pc: u16) {
+    warn!("Executing stop at {:#06x?}", pc);
 }
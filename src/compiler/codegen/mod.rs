@@ -203,6 +203,8 @@ enum EpilogueDescription {
         target: JumpDescription,
         skip_label: Option<DynamicLabel>,
     },
+    /// Continue to the next instruction, but return control to the system first
+    Exit,
 }
 
 impl Default for EpilogueDescription {
@@ -390,6 +392,13 @@ fn generate_epilogue(
             base_addr,
             labels,
         ),
+        EpilogueDescription::Exit => {
+            dynasm!(ops
+                ; mov r13w, WORD pc.wrapping_add(inst.size()) as _
+                ; add QWORD [r14], DWORD inst.cycles as _
+                ; jmp -> exit
+            );
+        }
         EpilogueDescription::Jump { target, skip_label } => {
             match target {
                 JumpDescription::Static(target_pc) => generate_static_jump_epilogue(
@@ -437,7 +446,8 @@ fn generate_oneoff_epilogue(ops: &mut Assembler, desc: &EpilogueDescription, ins
         );
     };
     match desc {
-        EpilogueDescription::Default => {
+        // Only single byte instructions exit, and those are never executed as oneoffs
+        EpilogueDescription::Default | EpilogueDescription::Exit => {
             dynasm!(ops
                 ; add r13w, WORD inst.size() as _
                 ;; epilogue(ops)
@@ -47,7 +47,7 @@ pub fn repack_cpu_state(ops: &mut Assembler) {
 
 pub fn setup_limit_address(ops: &mut Assembler) {
     dynasm!(ops
-        ; test r13d, 0x100
+        ; test r13d, 0x10000
         ; cmovz r15, [rsp + 0x20]
         ; cmovnz r15, [rsp + 0x28]
     );
@@ -55,14 +55,14 @@ pub fn setup_limit_address(ops: &mut Assembler) {
 
 pub fn int_enable(ops: &mut Assembler) {
     dynasm!(ops
-        ; or r13d, 0x100
+        ; or r13d, 0x10000
         ; mov r15, [rsp + 0x28]
     );
 }
 
 pub fn int_disable(ops: &mut Assembler) {
     dynasm!(ops
-        ; and r13d, 0xff
+        ; and r13d, 0xffff
         ; mov r15, [rsp + 0x20]
     );
 }
@@ -362,3 +362,71 @@ pub fn print_regs(ops: &mut Assembler) {
         ; pop r13
     );
 }
+
+#[cfg(test)]
+mod test {
+    use std::mem;
+
+    use super::*;
+    use crate::cpu_state::CpuState;
+
+    const DISABLED_LIMIT: u64 = 0x1111;
+    const ENABLED_LIMIT: u64 = 0x2222;
+
+    /// Unpack `state` into registers, run `body` on them and pack them back up, returning the
+    /// limit that ends up in r15
+    fn run_on_state(body: fn(&mut Assembler), state: &mut CpuState) -> u64 {
+        let mut ops = Assembler::new().unwrap();
+        let offset = ops.offset();
+        dynasm!(ops
+            ; push rbx
+            ; push r12
+            ; push r13
+            ; push r15
+            ; sub rsp, 0x38
+            ; mov r8, QWORD DISABLED_LIMIT as _
+            ; mov [rsp + 0x20], r8
+            ; mov r8, QWORD ENABLED_LIMIT as _
+            ; mov [rsp + 0x28], r8
+            ; xor r15, r15
+            ;; unpack_cpu_state(&mut ops)
+            ;; body(&mut ops)
+            ;; repack_cpu_state(&mut ops)
+            ; mov rax, r15
+            ; add rsp, 0x38
+            ; pop r15
+            ; pop r13
+            ; pop r12
+            ; pop rbx
+            ; ret
+        );
+        let buf = ops.finalize().unwrap();
+        let f: extern "sysv64" fn(*mut CpuState) -> u64 =
+            unsafe { mem::transmute(buf.ptr(offset)) };
+        f(state)
+    }
+
+    #[test]
+    fn intenable_is_kept_apart_from_pc() {
+        // Bit 8 of pc is set, which mustn't be mistaken for intenable
+        let mut state = CpuState {
+            pc: 0x0100,
+            ..CpuState::new()
+        };
+        assert_eq!(
+            run_on_state(setup_limit_address, &mut state),
+            DISABLED_LIMIT
+        );
+        assert_eq!((state.pc, state.intenable), (0x0100, false));
+
+        state.pc = 0x0000;
+        assert_eq!(run_on_state(int_enable, &mut state), ENABLED_LIMIT);
+        assert_eq!((state.pc, state.intenable), (0x0000, true));
+        assert_eq!(run_on_state(setup_limit_address, &mut state), ENABLED_LIMIT);
+
+        // Disabling interrupts leaves the high byte of pc alone
+        state.pc = 0x1234;
+        assert_eq!(run_on_state(int_disable, &mut state), DISABLED_LIMIT);
+        assert_eq!((state.pc, state.intenable), (0x1234, false));
+    }
+}
@@ -47,6 +47,12 @@ impl CycleState {
         set(&self.cycle, get(&self.cycle) + count)
     }
 
+    /// Move the cycle count to an arbitrary point, for restoring a saved state.  The limits have
+    /// to be set up again afterwards.
+    pub fn set_cycle(&self, val: u64) {
+        set(&self.cycle, val)
+    }
+
     /// Get the current cycle count.
     pub fn cycle(&self) -> u64 {
         get(&self.cycle)
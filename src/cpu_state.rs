@@ -10,6 +10,8 @@ pub struct CpuState {
     pub de: u16,
     pub hl: u16,
     pub intenable: bool,
+    /// Set by the compiled code when a HALT is executed, cleared once the cpu wakes up
+    pub halted: bool,
 }
 
 impl CpuState {
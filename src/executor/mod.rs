@@ -114,6 +114,33 @@ where
             }
         }
     }
+
+    /// Drop all compiled code, for when memory has been replaced wholesale and the versions of
+    /// pages can no longer be trusted to tell whether code is stale
+    pub fn clear(&mut self) {
+        debug!("Evicted all {} blocks", self.cache.len());
+        self.cache.clear();
+    }
+
+    /// Drop the compiled code for a page that can never be mapped again
+    pub fn evict(&mut self, id: &I) {
+        if self.cache.remove(id).is_some() {
+            debug!("Evicted block {:?}", id);
+        }
+    }
+}
+
+impl<I, T> Executor<I, T> {
+    /// Compile a block without caching it, for code that doesn't correspond to a real page
+    pub fn compile_uncached(&self, base_addr: u16, data: &[u8]) -> Result<CodeBlock<T>, Error> {
+        Ok(compile(
+            base_addr,
+            data,
+            self.bus,
+            &self.oneoffs,
+            &self.compile_options,
+        )?)
+    }
 }
 
 impl ExecutorOptions {
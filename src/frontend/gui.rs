@@ -1,5 +1,8 @@
+use std::cell::Cell;
 use std::error::Error as StdError;
+use std::fs;
 use std::mem;
+use std::rc::Rc;
 use std::time::{Duration, Instant};
 
 use glium::{
@@ -21,16 +24,52 @@ use log::*;
 use crate::{
     executor::ExecutorOptions,
     gb::{
-        devices::ppu::{Frame, FRAME_COLS, FRAME_ROWS},
-        Gb,
+        devices::{
+            ppu::{Frame, FRAME_COLS, FRAME_ROWS},
+            Button,
+        },
+        gbs, Gb, GbOptions,
     },
     Args,
 };
 
+use super::{movie::Movie, rewind::Rewind, state_slot_path};
+
 type GlColour = (u8, u8, u8);
 
+const TITLE: &str = "JIT Gameboy Emulator";
+
+/// Frames between the full states kept for rewinding, the rest are stored as changes from them
+const REWIND_KEYFRAME_INTERVAL: usize = 30;
+
 pub fn run(args: Args) -> Result<(), Box<dyn StdError>> {
-    let mut gb = Gb::new(&args.bios, &args.rom, ExecutorOptions::new(&args))?;
+    if gbs::is_gbs_path(&args.rom) {
+        return Err(
+            "GBS files can only be played in headless mode, use --wav to record them".into(),
+        );
+    }
+    let mut gb = Gb::new(
+        args.bios.as_ref(),
+        &args.rom,
+        GbOptions::new(&args),
+        ExecutorOptions::new(&args),
+    )?;
+
+    if let Some(slot) = args.load_state {
+        let path = state_slot_path(&args.rom, slot);
+        gb.load_state(&fs::read(&path)?)?;
+        info!("Loaded state from {}", path.display());
+    }
+    let mut movie = Movie::from_args(&args, &mut gb, args.load_state.is_none())?;
+    // While a movie runs the buttons only change between frames, as they do when it's played back
+    let movie_running = movie.is_some();
+    // The buttons held on the host, which loading a state mustn't undo
+    let mut held = 0;
+
+    let title = match gb.cartridge_header().title.as_str() {
+        "" => TITLE.to_string(),
+        game => format!("{} - {}", TITLE, game),
+    };
 
     let event_loop = EventLoop::new();
     let wb = WindowBuilder::new()
@@ -39,7 +78,7 @@ pub fn run(args: Args) -> Result<(), Box<dyn StdError>> {
             args.screen_dimensions.1,
         ))
         .with_resizable(false)
-        .with_title("JIT Gameboy Emulator");
+        .with_title(&title);
 
     let cb = ContextBuilder::new();
 
@@ -47,6 +86,13 @@ pub fn run(args: Args) -> Result<(), Box<dyn StdError>> {
 
     let pixels = display.get_framebuffer_dimensions();
 
+    let rumble = Rc::new(Cell::new(false));
+    gb.set_rumble_callback({
+        let rumble = rumble.clone();
+        move |on| rumble.set(on)
+    });
+    let mut shown_rumble = false;
+
     let texture = Texture2d::empty(&display, FRAME_COLS as _, FRAME_ROWS as _)?;
 
     let buffer: Buffer<[GlColour]> = Buffer::empty_unsized(
@@ -56,16 +102,45 @@ pub fn run(args: Args) -> Result<(), Box<dyn StdError>> {
         BufferMode::Persistent,
     )?;
 
+    let mut slot = 1;
+
     let mut last_frame = Instant::now();
     let frame_time = Duration::from_secs_f64(0.01674270629);
 
-    let mut run_frame = move |last_frame: &mut Instant| {
+    // Going back would leave the movie out of step with the machine
+    let mut rewind = if args.rewind_seconds > 0.0 && !movie_running {
+        let capacity = (args.rewind_seconds / frame_time.as_secs_f64()) as usize;
+        let mut rewind = Rewind::new(capacity, REWIND_KEYFRAME_INTERVAL);
+        let (state, pages) = gb.save_state_pages();
+        rewind.push(state, pages);
+        Some(rewind)
+    } else {
+        None
+    };
+    let mut rewind_held = false;
+
+    let mut run_frame = move |gb: &mut Gb, last_frame: &mut Instant, held: u8, rewinding: bool| {
         let start = Instant::now();
+        if let (Some(rewind), true) = (&mut rewind, rewinding) {
+            if let Some(state) = rewind.step_back() {
+                gb.load_state(&state)
+                    .expect("Loading a state from the rewind buffer should succeed");
+                gb.set_buttons(held);
+            }
+        }
         debug!("Simulating GB");
-        let frame = gb
-            .run_frame()
-            .expect("Experienced error while producing frame");
+        // There's no audio output yet, so the samples are dropped
+        let frame = match &mut movie {
+            Some(movie) => movie.run_frame(gb, held),
+            None => gb.run_frame().map_err(Into::into),
+        }
+        .expect("Experienced error while producing frame")
+        .frame;
         debug!("Simulation finished");
+        if let Some(rewind) = &mut rewind {
+            let (state, pages) = gb.save_state_pages();
+            rewind.push(state, pages);
+        }
 
         let data = transcribe_frame(&*frame);
         let slice = buffer.as_slice();
@@ -98,6 +173,16 @@ pub fn run(args: Args) -> Result<(), Box<dyn StdError>> {
         );
         surface.finish().expect("Surface failed to draw");
 
+        if rumble.get() != shown_rumble {
+            shown_rumble = rumble.get();
+            let shown_title = if shown_rumble {
+                format!("{} (rumble)", title)
+            } else {
+                title.clone()
+            };
+            display.gl_window().window().set_title(&shown_title);
+        }
+
         *last_frame += frame_time;
         debug!("Frame took {:#?}", Instant::now() - start);
     };
@@ -106,12 +191,26 @@ pub fn run(args: Args) -> Result<(), Box<dyn StdError>> {
         debug!("Event: {:?}", event);
 
         match event {
+            Event::WindowEvent {
+                window_id: _,
+                event: WindowEvent::CloseRequested,
+            } => {
+                *flow = ControlFlow::Exit;
+                return;
+            }
+            // The event loop exits the process without dropping anything, so save explicitly
+            Event::LoopDestroyed => {
+                if let Err(err) = gb.save() {
+                    error!("Failed to write save file: {}", err);
+                }
+                return;
+            }
             Event::NewEvents(StartCause::ResumeTimeReached {
                 start: _,
                 requested_resume: _,
             }) => {
                 if !args.wait {
-                    run_frame(&mut last_frame)
+                    run_frame(&mut gb, &mut last_frame, held, rewind_held)
                 }
             }
             #[allow(deprecated)]
@@ -123,15 +222,31 @@ pub fn run(args: Args) -> Result<(), Box<dyn StdError>> {
                         input:
                             KeyboardInput {
                                 scancode: _,
-                                state: ElementState::Pressed,
-                                virtual_keycode: Some(VirtualKeyCode::N),
+                                state,
+                                virtual_keycode: Some(key),
                                 modifiers: _,
                             },
                         is_synthetic: false,
                     },
             } => {
-                if args.wait {
-                    run_frame(&mut last_frame)
+                let pressed = state == ElementState::Pressed;
+                if let Some(button) = map_key(key) {
+                    match pressed {
+                        true => held |= button.bit(),
+                        false => held &= !button.bit(),
+                    }
+                    if !movie_running {
+                        gb.set_button(button, pressed);
+                    }
+                }
+                if key == VirtualKeyCode::N && pressed && args.wait {
+                    run_frame(&mut gb, &mut last_frame, held, rewind_held)
+                }
+                if key == VirtualKeyCode::R {
+                    rewind_held = pressed;
+                }
+                if pressed {
+                    handle_state_key(key, &mut gb, &args.rom, &mut slot, held, !movie_running);
                 }
             }
             _ => {}
@@ -145,6 +260,60 @@ pub fn run(args: Args) -> Result<(), Box<dyn StdError>> {
     });
 }
 
+/// The number keys pick a save state slot, F5 saves to it and F8 loads from it if `allow_load`,
+/// keeping the `held` buttons held rather than those in the state
+fn handle_state_key(
+    key: VirtualKeyCode,
+    gb: &mut Gb,
+    rom: &str,
+    slot: &mut u8,
+    held: u8,
+    allow_load: bool,
+) {
+    use VirtualKeyCode::*;
+    let digits = [Key0, Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9];
+    if let Some(digit) = digits.iter().position(|digit| *digit == key) {
+        *slot = digit as u8;
+        info!("Selected save state slot {}", slot);
+        return;
+    }
+
+    if key == F8 && !allow_load {
+        warn!("Loading save states is turned off while a movie is running");
+        return;
+    }
+
+    let path = state_slot_path(rom, *slot);
+    let result = match key {
+        F5 => fs::write(&path, gb.save_state()).map_err(|err| err.into()),
+        F8 => fs::read(&path)
+            .map_err(|err| err.into())
+            .and_then(|data| gb.load_state(&data))
+            .map(|()| gb.set_buttons(held)),
+        _ => return,
+    };
+    match result {
+        Ok(()) if key == F5 => info!("Saved state to {}", path.display()),
+        Ok(()) => info!("Loaded state from {}", path.display()),
+        Err(err) => error!("Save state slot {} failed: {}", slot, err),
+    }
+}
+
+fn map_key(key: VirtualKeyCode) -> Option<Button> {
+    use VirtualKeyCode::*;
+    match key {
+        Right => Some(Button::Right),
+        Left => Some(Button::Left),
+        Up => Some(Button::Up),
+        Down => Some(Button::Down),
+        X => Some(Button::A),
+        Z => Some(Button::B),
+        Back => Some(Button::Select),
+        Return => Some(Button::Start),
+        _ => None,
+    }
+}
+
 pub fn transcribe_frame(frame: &Frame) -> [GlColour; FRAME_ROWS * FRAME_COLS] {
     let mut result = [(0, 0, 0); FRAME_ROWS * FRAME_COLS];
     for (src, dst) in frame
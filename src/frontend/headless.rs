@@ -1,16 +1,72 @@
 use std::error::Error as StdError;
+use std::fs;
 
 use log::*;
 
-use crate::{executor::ExecutorOptions, gb::Gb, Args};
+use crate::{
+    executor::ExecutorOptions,
+    gb::{gbs, Gb, GbOptions},
+    Args,
+};
+
+use super::{
+    movie::{self, Movie},
+    state_slot_path,
+    wav::WavRecorder,
+};
 
 pub fn run(args: Args) -> Result<(), Box<dyn StdError>> {
-    let mut gb = Gb::new(&args.bios, &args.rom, ExecutorOptions::new(&args))?;
+    let mut gb = if gbs::is_gbs_path(&args.rom) {
+        Gb::new_gbs(
+            &args.rom,
+            args.track,
+            GbOptions::new(&args),
+            ExecutorOptions::new(&args),
+        )?
+    } else {
+        Gb::new(
+            args.bios.as_ref(),
+            &args.rom,
+            GbOptions::new(&args),
+            ExecutorOptions::new(&args),
+        )?
+    };
+
+    if let Some(slot) = args.load_state {
+        let path = state_slot_path(&args.rom, slot);
+        gb.load_state(&fs::read(&path)?)?;
+        info!("Loaded state from {}", path.display());
+    }
+
+    let mut recorder = match &args.wav {
+        Some(path) => Some(WavRecorder::new(path, args.wav_rate, args.wav_stems)?),
+        None => None,
+    };
+
+    let mut movie = Movie::from_args(&args, &mut gb, args.load_state.is_none())?;
 
     let mut i = 0;
-    loop {
-        gb.run_frame()?;
-        debug!("Finished frame {}", i);
+    while args.frames.map_or(true, |frames| i < frames) {
+        let output = match &mut movie {
+            Some(movie) if movie.is_finished() || movie.diverged().is_some() => break,
+            Some(movie) => movie.run_frame(&mut gb, 0)?,
+            None => gb.run_frame()?,
+        };
+        debug!("Finished frame {} with {} samples", i, output.samples.len());
+        if let Some(recorder) = &mut recorder {
+            recorder.record(&output.samples)?;
+        }
         i += 1;
     }
+
+    if let Some(slot) = args.save_state {
+        let path = state_slot_path(&args.rom, slot);
+        fs::write(&path, gb.save_state())?;
+        info!("Saved state to {}", path.display());
+    }
+
+    match movie.as_ref().and_then(Movie::diverged) {
+        Some(frame) => Err(movie::Error::Diverged(frame).into()),
+        None => Ok(()),
+    }
 }
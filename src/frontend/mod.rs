@@ -1,2 +1,12 @@
+use std::path::{Path, PathBuf};
+
 pub mod gui;
 pub mod headless;
+pub mod movie;
+pub mod rewind;
+pub mod wav;
+
+/// Save states are kept next to the ROM, with the slot number in the extension
+pub fn state_slot_path<P: AsRef<Path>>(rom: P, slot: u8) -> PathBuf {
+    rom.as_ref().with_extension(format!("ss{}", slot))
+}
@@ -0,0 +1,377 @@
+//! Movies, recordings of the joypad from a known start that can be played back to reproduce a run
+//! exactly.  Nothing the machine does depends on the host once the real-time clock is kept on
+//! emulated time, so the same buttons from the same state give the same frames.
+//!
+//! A movie is a header naming the ROM by its checksum and holding the save state it starts from,
+//! even when that's power-on, so that battery-backed RAM comes along too.  The renderer is noted
+//! in it as well, since the pixel FIFO changes the timing of mode 3 and so everything after it.
+//! After the header there's a record for each frame of the buttons held during it, followed by a
+//! hash of the machine's state after it in movies recorded with `--verify`.
+
+use std::convert::TryInto;
+use std::error::Error as StdError;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use log::*;
+use quick_error::quick_error;
+
+use crate::{
+    gb::{state, FrameOutput, Gb},
+    Args,
+};
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        Io(err: io::Error) {
+            from()
+            display("Failed to access movie file: {}", err)
+        }
+        BadMagic {
+            display("Not a movie file")
+        }
+        UnsupportedVersion(version: u32) {
+            display("Unsupported movie version {}, expected {}", version, VERSION)
+        }
+        WrongCartridge {
+            display("Movie was recorded with a different cartridge")
+        }
+        WrongRenderer(pixel_fifo: bool) {
+            display("Movie was recorded {} --pixel-fifo, play it back the same way",
+                if *pixel_fifo { "with" } else { "without" })
+        }
+        Truncated {
+            display("Movie file ends partway through its header or a frame")
+        }
+        Diverged(frame: u64) {
+            display("Playback diverged from the recording at frame {}", frame)
+        }
+    }
+}
+
+const MAGIC: &[u8; 8] = b"GBJITMOV";
+
+const VERSION: u32 = 1;
+
+const FLAG_POWER_ON: u8 = 0x1;
+const FLAG_HASHES: u8 = 0x2;
+const FLAG_PIXEL_FIFO: u8 = 0x4;
+
+pub struct Recorder {
+    file: BufWriter<File>,
+    hashes: bool,
+}
+
+struct Frame {
+    buttons: u8,
+    hash: Option<u64>,
+}
+
+pub struct Player {
+    frames: Vec<Frame>,
+    next: usize,
+    diverged: Option<u64>,
+}
+
+pub enum Movie {
+    Record(Recorder),
+    Play(Player),
+}
+
+impl Movie {
+    /// Start recording or playing back a movie if the arguments ask for one.  Recording starts
+    /// from the state the machine is in now, which is power-on unless a state has been loaded.
+    pub fn from_args(
+        args: &Args,
+        gb: &mut Gb,
+        power_on: bool,
+    ) -> Result<Option<Self>, Box<dyn StdError>> {
+        if let Some(path) = &args.record_movie {
+            let recorder = Recorder::create(path, gb, power_on, args.verify)?;
+            info!("Recording movie to {}", path);
+            Ok(Some(Movie::Record(recorder)))
+        } else if let Some(path) = &args.play_movie {
+            let (player, start_state) = Player::open(path, gb.rom_checksum(), gb.pixel_fifo())?;
+            gb.load_state(&start_state)?;
+            info!("Playing movie {} of {} frames", path, player.frames.len());
+            Ok(Some(Movie::Play(player)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Run a frame with the buttons from the movie, or with `held` while recording and once
+    /// playback has finished
+    pub fn run_frame(&mut self, gb: &mut Gb, held: u8) -> Result<FrameOutput, Box<dyn StdError>> {
+        match self {
+            Movie::Record(recorder) => {
+                gb.set_buttons(held);
+                let output = gb.run_frame()?;
+                recorder.record_frame(gb)?;
+                Ok(output)
+            }
+            Movie::Play(player) => match player.frames.get(player.next) {
+                Some(frame) => {
+                    gb.set_buttons(frame.buttons);
+                    let output = gb.run_frame()?;
+                    if let (Some(hash), None) = (frame.hash, player.diverged) {
+                        if hash != state::checksum(&gb.save_state()) {
+                            error!("{}", Error::Diverged(player.next as u64));
+                            player.diverged = Some(player.next as u64);
+                        }
+                    }
+                    player.next += 1;
+                    if player.next == player.frames.len() {
+                        info!("Movie finished");
+                    }
+                    Ok(output)
+                }
+                None => {
+                    gb.set_buttons(held);
+                    Ok(gb.run_frame()?)
+                }
+            },
+        }
+    }
+
+    /// Whether every frame of a movie being played back has run
+    pub fn is_finished(&self) -> bool {
+        match self {
+            Movie::Record(_) => false,
+            Movie::Play(player) => player.next >= player.frames.len(),
+        }
+    }
+
+    /// The first frame whose state didn't match the hash recorded for it, numbered from 0
+    pub fn diverged(&self) -> Option<u64> {
+        match self {
+            Movie::Record(_) => None,
+            Movie::Play(player) => player.diverged,
+        }
+    }
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        gb: &Gb,
+        power_on: bool,
+        hashes: bool,
+    ) -> Result<Self, Error> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let mut flags = 0;
+        if power_on {
+            flags |= FLAG_POWER_ON;
+        }
+        if hashes {
+            flags |= FLAG_HASHES;
+        }
+        if gb.pixel_fifo() {
+            flags |= FLAG_PIXEL_FIFO;
+        }
+        let start_state = gb.save_state();
+
+        file.write_all(MAGIC)?;
+        file.write_all(&VERSION.to_le_bytes())?;
+        file.write_all(&gb.rom_checksum().to_le_bytes())?;
+        file.write_all(&[flags])?;
+        file.write_all(&(start_state.len() as u32).to_le_bytes())?;
+        file.write_all(&start_state)?;
+        file.flush()?;
+
+        Ok(Recorder { file, hashes })
+    }
+
+    /// Add the frame that just ran.  Each one is flushed straight away, so a movie of a crash
+    /// has everything up to it.
+    fn record_frame(&mut self, gb: &Gb) -> Result<(), Error> {
+        self.file.write_all(&[gb.buttons()])?;
+        if self.hashes {
+            let hash = state::checksum(&gb.save_state());
+            self.file.write_all(&hash.to_le_bytes())?;
+        }
+        Ok(self.file.flush()?)
+    }
+}
+
+impl Player {
+    /// Read a movie made with the cartridge whose ROM has `rom_checksum` and the same renderer,
+    /// returning it along with the state it starts from
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        rom_checksum: u64,
+        pixel_fifo: bool,
+    ) -> Result<(Self, Vec<u8>), Error> {
+        Self::parse(&fs::read(path)?, rom_checksum, pixel_fifo)
+    }
+
+    fn parse(data: &[u8], rom_checksum: u64, pixel_fifo: bool) -> Result<(Self, Vec<u8>), Error> {
+        let mut rest = data;
+        let mut take = |len: usize| {
+            if len > rest.len() {
+                return Err(Error::Truncated);
+            }
+            let (head, tail) = rest.split_at(len);
+            rest = tail;
+            Ok(head)
+        };
+
+        if take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err(Error::BadMagic);
+        }
+        let version = u32::from_le_bytes(take(4)?.try_into().unwrap());
+        if version != VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        if u64::from_le_bytes(take(8)?.try_into().unwrap()) != rom_checksum {
+            return Err(Error::WrongCartridge);
+        }
+        let flags = take(1)?[0];
+        if (flags & FLAG_PIXEL_FIFO != 0) != pixel_fifo {
+            return Err(Error::WrongRenderer(!pixel_fifo));
+        }
+        let state_len = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+        let start_state = take(state_len)?.to_vec();
+        if flags & FLAG_POWER_ON != 0 {
+            info!("Movie starts from power-on");
+        }
+
+        let hashes = flags & FLAG_HASHES != 0;
+        let frame_len = if hashes { 9 } else { 1 };
+        if rest.len() % frame_len != 0 {
+            return Err(Error::Truncated);
+        }
+        let frames = rest
+            .chunks(frame_len)
+            .map(|frame| Frame {
+                buttons: frame[0],
+                hash: match hashes {
+                    true => Some(u64::from_le_bytes(frame[1..].try_into().unwrap())),
+                    false => None,
+                },
+            })
+            .collect();
+
+        let player = Player {
+            frames,
+            next: 0,
+            diverged: None,
+        };
+        Ok((player, start_state))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::executor::ExecutorOptions;
+    use crate::gb::GbOptions;
+
+    const FRAMES: usize = 20;
+
+    /// A GBS file whose play routine adds the directions read from the joypad to 0xc000 every
+    /// frame, so the buttons held end up in the machine's state
+    fn button_reader(path: &Path) -> Gb {
+        let mut file = vec![0; 0x70];
+        file[0..4].copy_from_slice(b"GBS\x01");
+        file[4] = 1;
+        file[5] = 1;
+        file[0x06..0x08].copy_from_slice(&0x400u16.to_le_bytes());
+        file[0x08..0x0a].copy_from_slice(&0x400u16.to_le_bytes());
+        file[0x0a..0x0c].copy_from_slice(&0x401u16.to_le_bytes());
+        file[0x0c..0x0e].copy_from_slice(&0xdffeu16.to_le_bytes());
+        file.extend_from_slice(&[
+            0xc9, // init: ret
+            0x3e, 0x20, 0xe0, 0x00, // play: ld a, 0x20; ldh (P1), a
+            0xf0, 0x00, 0x47, // ldh a, (P1); ld b, a
+            0xfa, 0x00, 0xc0, 0x80, 0xea, 0x00, 0xc0, // ld a, (0xc000); add b; ld (0xc000), a
+            0xc9, // ret
+        ]);
+        fs::write(path, file).unwrap();
+
+        let executor_options = ExecutorOptions {
+            compile_options: Default::default(),
+            disassembly_logfile: None,
+        };
+        Gb::new_gbs(path, None, GbOptions::default(), executor_options).unwrap()
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("gbjit-{}-{}", std::process::id(), name))
+    }
+
+    /// Play a movie back from its start, returning the frame it diverged at
+    fn play(gb: &mut Gb, path: &Path) -> Option<u64> {
+        let (player, start_state) = Player::open(path, gb.rom_checksum(), false).unwrap();
+        gb.load_state(&start_state).unwrap();
+        let mut movie = Movie::Play(player);
+        while !movie.is_finished() {
+            movie.run_frame(gb, 0).unwrap();
+        }
+        movie.diverged()
+    }
+
+    #[test]
+    fn playback_matches_recording() {
+        let (gbs_path, movie_path) = (temp_path("movie.gbs"), temp_path("movie.mov"));
+        let mut gb = button_reader(&gbs_path);
+        let recorder = Recorder::create(&movie_path, &gb, true, true).unwrap();
+        let mut movie = Movie::Record(recorder);
+        for frame in 0..FRAMES {
+            movie.run_frame(&mut gb, (frame * 5 % 16) as u8).unwrap();
+        }
+        drop(movie);
+        let recorded = state::checksum(&gb.save_state());
+
+        assert_eq!(play(&mut gb, &movie_path), None);
+        assert_eq!(state::checksum(&gb.save_state()), recorded);
+
+        // Each frame's record is the buttons followed by the hash, and they end the file
+        let mut data = fs::read(&movie_path).unwrap();
+        let len = data.len();
+        data[len - (FRAMES - 7) * 9] ^= 0x0f;
+        fs::write(&movie_path, data).unwrap();
+        assert_eq!(play(&mut gb, &movie_path), Some(7));
+
+        fs::remove_file(gbs_path).unwrap();
+        fs::remove_file(movie_path).unwrap();
+    }
+
+    #[test]
+    fn parses_frames_and_hashes() {
+        let mut data = Vec::new();
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&VERSION.to_le_bytes());
+        data.extend_from_slice(&0x1234u64.to_le_bytes());
+        data.push(FLAG_HASHES);
+        data.extend_from_slice(&3u32.to_le_bytes());
+        data.extend_from_slice(&[7, 8, 9]);
+        for frame in 0..4u8 {
+            data.push(frame);
+            data.extend_from_slice(&(frame as u64 * 100).to_le_bytes());
+        }
+
+        let (player, start_state) = Player::parse(&data, 0x1234, false).unwrap();
+        assert_eq!(start_state, vec![7, 8, 9]);
+        assert_eq!(player.frames.len(), 4);
+        assert_eq!(player.frames[2].buttons, 2);
+        assert_eq!(player.frames[3].hash, Some(300));
+
+        assert!(matches!(
+            Player::parse(&data, 1, false),
+            Err(Error::WrongCartridge)
+        ));
+        assert!(matches!(
+            Player::parse(&data, 0x1234, true),
+            Err(Error::WrongRenderer(false))
+        ));
+        assert!(matches!(
+            Player::parse(&data[..data.len() - 1], 0x1234, false),
+            Err(Error::Truncated)
+        ));
+    }
+}
@@ -0,0 +1,229 @@
+//! A rewind buffer of the machine's state after each of the last few seconds of frames.  Every
+//! `keyframe_interval` frames the whole save state is kept, and the frames in between are stored
+//! as their difference from it.  Pages of memory still at the version they were at in the
+//! keyframe are known to be unchanged and skipped without being compared, and the rest of the
+//! difference is mostly long runs of zeros that get run-length encoded away.
+
+use std::collections::VecDeque;
+use std::ops::Range;
+
+use crate::gb::state::Page;
+
+/// Unchanged bytes it takes to end a run of changed ones, shorter gaps are cheaper to store as
+/// part of the run than as the start of a new one
+const MIN_GAP: usize = 8;
+
+struct Group {
+    keyframe: Vec<u8>,
+    pages: Vec<Page>,
+    deltas: Vec<Vec<u8>>,
+}
+
+pub struct Rewind {
+    groups: VecDeque<Group>,
+    /// Most frames kept, the oldest group goes once a new one would take it over
+    capacity: usize,
+    keyframe_interval: usize,
+    len: usize,
+}
+
+impl Group {
+    fn len(&self) -> usize {
+        1 + self.deltas.len()
+    }
+}
+
+impl Rewind {
+    pub fn new(capacity: usize, keyframe_interval: usize) -> Self {
+        Rewind {
+            groups: VecDeque::new(),
+            capacity,
+            keyframe_interval,
+            len: 0,
+        }
+    }
+
+    /// Add a state along with its pages, as given by `Gb::save_state_pages`
+    pub fn push(&mut self, state: Vec<u8>, pages: Vec<Page>) {
+        match self.groups.back_mut() {
+            Some(group) if group.len() < self.keyframe_interval => {
+                let unchanged = unchanged_pages(&group.pages, &pages);
+                group
+                    .deltas
+                    .push(encode(&group.keyframe, &state, &unchanged))
+            }
+            _ => self.groups.push_back(Group {
+                keyframe: state,
+                pages,
+                deltas: Vec::new(),
+            }),
+        }
+        self.len += 1;
+
+        while self.len > self.capacity && self.groups.len() > 1 {
+            let dropped = self.groups.pop_front().expect("Group should exist");
+            self.len -= dropped.len();
+        }
+    }
+
+    /// Go back to the state two before the newest, which stays on the buffer while the two after
+    /// it are dropped.  Running a frame from it shows the frame before the one that led to the
+    /// newest state.  Once there's nothing further back, the oldest state is returned every time.
+    pub fn step_back(&mut self) -> Option<Vec<u8>> {
+        for _ in 0..self.len.saturating_sub(1).min(2) {
+            self.pop();
+        }
+        self.newest()
+    }
+
+    fn newest(&self) -> Option<Vec<u8>> {
+        let group = self.groups.back()?;
+        Some(match group.deltas.last() {
+            Some(delta) => decode(&group.keyframe, delta),
+            None => group.keyframe.clone(),
+        })
+    }
+
+    /// Drop the newest state
+    fn pop(&mut self) {
+        if let Some(group) = self.groups.back_mut() {
+            self.len -= 1;
+            if group.deltas.pop().is_none() {
+                self.groups.pop_back();
+            }
+        }
+    }
+}
+
+/// Where the pages that are at the same version as in the keyframe are in the state
+fn unchanged_pages(keyframe: &[Page], pages: &[Page]) -> Vec<Range<usize>> {
+    keyframe
+        .iter()
+        .zip(pages)
+        .filter(|(old, new)| old == new)
+        .map(|(_, page)| page.offset..page.offset + page.len)
+        .collect()
+}
+
+/// Encode `state` as its XOR with `base`, in runs of a count of unchanged bytes to skip followed
+/// by a count of changed bytes and their XORs.  Bytes past the end of `base` are XORed with 0,
+/// and those in the sorted `unchanged` ranges are taken to match without looking at them.
+fn encode(base: &[u8], state: &[u8], unchanged: &[Range<usize>]) -> Vec<u8> {
+    let xor = |i: usize| state[i] ^ base.get(i).copied().unwrap_or(0);
+    let mut out = Vec::new();
+    out.extend_from_slice(&(state.len() as u32).to_le_bytes());
+
+    let mut ranges = unchanged.iter().peekable();
+    let mut i = 0;
+    let mut changed = std::iter::from_fn(|| {
+        while i < state.len() {
+            while ranges.next_if(|range| range.end <= i).is_some() {}
+            match ranges.peek() {
+                Some(range) if range.start <= i => i = range.end,
+                _ => {
+                    i += 1;
+                    if xor(i - 1) != 0 {
+                        return Some(i - 1);
+                    }
+                }
+            }
+        }
+        None
+    })
+    .peekable();
+
+    let mut pos = 0;
+    while let Some(run_start) = changed.next() {
+        let mut end = run_start + 1;
+        while let Some(next) = changed.next_if(|next| *next - end < MIN_GAP) {
+            end = next + 1;
+        }
+        out.extend_from_slice(&((run_start - pos) as u32).to_le_bytes());
+        out.extend_from_slice(&((end - run_start) as u32).to_le_bytes());
+        out.extend((run_start..end).map(xor));
+        pos = end;
+    }
+    out
+}
+
+fn decode(base: &[u8], delta: &[u8]) -> Vec<u8> {
+    let word = |bytes: &[u8]| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+    let mut state = base.to_vec();
+    state.resize(word(delta), 0);
+
+    let mut pos = 0;
+    let mut rest = &delta[4..];
+    while !rest.is_empty() {
+        pos += word(rest);
+        let len = word(&rest[4..]);
+        for (dst, xor) in state[pos..pos + len].iter_mut().zip(&rest[8..8 + len]) {
+            *dst ^= xor;
+        }
+        pos += len;
+        rest = &rest[8 + len..];
+    }
+    state
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn steps_back_through_deltas() {
+        let mut rewind = Rewind::new(6, 3);
+        let states: Vec<Vec<u8>> = (0..8u8)
+            .map(|i| {
+                let mut state = vec![0; 100 + i as usize];
+                state[10] = i;
+                state[50..60].iter_mut().for_each(|b| *b = i * 3);
+                state
+            })
+            .collect();
+        for state in &states {
+            rewind.push(state.clone(), Vec::new());
+        }
+
+        // The first group of 3 was dropped to keep within capacity
+        assert_eq!(rewind.len, 5);
+        for state in states[3..].iter().rev() {
+            assert_eq!(rewind.newest().as_ref(), Some(state));
+            rewind.pop();
+        }
+        assert_eq!(rewind.newest(), None);
+    }
+
+    #[test]
+    fn delta_skips_unchanged_bytes() {
+        let base = vec![7; 1000];
+        let mut state = base.clone();
+        state[500] = 0;
+        state.truncate(900);
+        let delta = encode(&base, &state, &[]);
+        assert!(delta.len() < 32);
+        assert_eq!(decode(&base, &delta), state);
+    }
+
+    #[test]
+    fn pages_at_the_keyframe_version_are_skipped() {
+        let page = |offset, version| Page {
+            offset,
+            len: 0x10,
+            version,
+        };
+        let keyframe = vec![page(0x10, 1), page(0x20, 4)];
+        let unchanged = unchanged_pages(&keyframe, &[page(0x10, 1), page(0x20, 5)]);
+        assert_eq!(unchanged, vec![0x10..0x20]);
+
+        let base = vec![0; 0x40];
+        let mut state = base.clone();
+        state[0x05] = 1;
+        state[0x15] = 2;
+        state[0x25] = 3;
+        // Only what's outside of the unchanged page is looked at
+        let mut expected = base.clone();
+        expected[0x05] = 1;
+        expected[0x25] = 3;
+        assert_eq!(decode(&base, &encode(&base, &state, &unchanged)), expected);
+    }
+}
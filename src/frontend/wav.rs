@@ -0,0 +1,317 @@
+//! Recording the APU's output to WAV files.  The APU puts out a sample every machine cycle, which
+//! is brought down to the file's rate in two band-limited steps: a lowpass FIR that only keeps
+//! every `DECIMATION`th sample, then a windowed sinc filter for the fractional step down to the
+//! final rate.  Both are Blackman-windowed sincs long enough that anything which would fold back
+//! into the output's passband is at least 74dB down before it gets the chance.
+
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::gb::devices::{apu, Sample};
+
+/// Ratio of the APU's rate to the rate between the two steps
+const DECIMATION: usize = 8;
+
+/// Transition width of a Blackman-windowed sinc, as a fraction of the sample rate, times its
+/// length.  Past the transition the sinc is 74dB down.
+const WINDOW_TRANSITION: f64 = 5.5;
+
+/// Fractional positions the filter is precomputed for
+const PHASES: usize = 256;
+
+/// How much of the band below the output's Nyquist frequency is kept, leaving room for the
+/// transition
+const PASSBAND: f64 = 0.9;
+
+/// Scale of the per-channel stems, whose samples are the channels' 4-bit digital output
+const STEM_SCALE: f32 = i16::MAX as f32 / 15.0;
+
+/// Space left before samples that have been consumed are dropped from the history
+const HISTORY_SLACK: usize = 4096;
+
+pub struct WavWriter {
+    file: BufWriter<File>,
+    data_len: u32,
+}
+
+impl WavWriter {
+    /// Start a 16 bit PCM file
+    pub fn create<P: AsRef<Path>>(path: P, channels: u16, rate: u32) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let block_align = channels * 2;
+
+        file.write_all(b"RIFF")?;
+        file.write_all(&36u32.to_le_bytes())?;
+        file.write_all(b"WAVEfmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        file.write_all(&1u16.to_le_bytes())?;
+        file.write_all(&channels.to_le_bytes())?;
+        file.write_all(&rate.to_le_bytes())?;
+        file.write_all(&(rate * block_align as u32).to_le_bytes())?;
+        file.write_all(&block_align.to_le_bytes())?;
+        file.write_all(&16u16.to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?;
+
+        Ok(WavWriter { file, data_len: 0 })
+    }
+
+    pub fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        for sample in samples {
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_len += samples.len() as u32 * 2;
+        Ok(())
+    }
+
+    /// Fill in the lengths in the header, so the file is valid up to here even if the process
+    /// never gets to finish it
+    pub fn update_header(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&(36 + self.data_len).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&self.data_len.to_le_bytes())?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()
+    }
+}
+
+/// Brings one stream of APU samples down to an output rate
+pub struct Resampler {
+    /// Taps of the first step's lowpass
+    decimator: Vec<f32>,
+    /// APU samples for the first step, ending with the newest
+    input: Vec<f32>,
+    /// APU samples until the first step next puts one out
+    countdown: usize,
+
+    /// Decimated samples per output sample
+    step: f64,
+    /// Position of the next output sample in `history`
+    pos: f64,
+    history: Vec<f32>,
+    /// Filter taps on each side of the centre
+    taps: usize,
+    /// The filter at each phase, with `2 * taps` taps each
+    filter: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(rate: u32) -> Self {
+        let apu_rate = apu::SAMPLE_RATE as f64;
+        let decimated_rate = apu_rate / DECIMATION as f64;
+        // Everything up to the passband is kept, and nothing may fold back into it, which
+        // anything between the Nyquist frequency and its mirror image in the passband does
+        let nyquist = 0.5 * decimated_rate.min(rate as f64);
+        let passband = PASSBAND * nyquist;
+        let stopband = 2.0 * nyquist - passband;
+
+        // The first step's stopband only has to start where its own output would fold back
+        let half_width = |transition: f64| (0.5 * WINDOW_TRANSITION / transition).ceil();
+        let decimator_width = half_width((decimated_rate - 2.0 * passband) / apu_rate);
+        let decimator_cutoff = 0.5 / DECIMATION as f64;
+        let mut decimator: Vec<f64> = (1 - decimator_width as isize..decimator_width as isize)
+            .map(|x| windowed_sinc(x as f64, decimator_cutoff, decimator_width))
+            .collect();
+        let gain: f64 = decimator.iter().sum();
+        decimator.iter_mut().for_each(|tap| *tap /= gain);
+
+        let step = decimated_rate / rate as f64;
+        // Cutoff as a fraction of the decimated rate, halfway through the transition
+        let cutoff = nyquist / decimated_rate;
+        let taps = half_width((stopband - passband) / decimated_rate) as usize;
+
+        let mut filter = Vec::with_capacity((PHASES + 1) * taps * 2);
+        for phase in 0..=PHASES {
+            let frac = phase as f64 / PHASES as f64;
+            for j in 0..taps * 2 {
+                let x = (j as isize - taps as isize + 1) as f64 - frac;
+                filter.push(windowed_sinc(x, cutoff, taps as f64) as f32);
+            }
+        }
+
+        Resampler {
+            input: vec![0.0; decimator.len() - 1],
+            decimator: decimator.into_iter().map(|tap| tap as f32).collect(),
+            countdown: DECIMATION,
+            step,
+            pos: taps as f64,
+            history: vec![0.0; taps],
+            taps,
+            filter,
+        }
+    }
+
+    /// Feed in samples at the APU's rate, adding any complete output samples to `out`
+    pub fn process(&mut self, input: impl Iterator<Item = f32>, out: &mut Vec<i16>) {
+        for sample in input {
+            self.input.push(sample);
+            self.countdown -= 1;
+            if self.countdown > 0 {
+                continue;
+            }
+            self.countdown = DECIMATION;
+            // The filter is symmetric, so it doesn't matter which way round it's applied
+            let window = &self.input[self.input.len() - self.decimator.len()..];
+            let sum = window.iter().zip(&self.decimator).map(|(x, h)| x * h).sum();
+            self.history.push(sum);
+
+            while (self.pos as usize) + self.taps < self.history.len() {
+                out.push(self.output());
+                self.pos += self.step;
+            }
+        }
+
+        let consumed = self.input.len() + 1 - self.decimator.len();
+        if consumed > HISTORY_SLACK {
+            self.input.drain(..consumed);
+        }
+        let consumed = (self.pos as usize).saturating_sub(self.taps);
+        if consumed > HISTORY_SLACK {
+            self.history.drain(..consumed);
+            self.pos -= consumed as f64;
+        }
+    }
+
+    fn output(&self) -> i16 {
+        let centre = self.pos as usize;
+        let phase = ((self.pos - centre as f64) * PHASES as f64).round() as usize;
+        let filter = &self.filter[phase * self.taps * 2..(phase + 1) * self.taps * 2];
+        let window = &self.history[centre + 1 - self.taps..=centre + self.taps];
+        let sum: f32 = window.iter().zip(filter).map(|(x, h)| x * h).sum();
+        sum.round().max(i16::MIN as f32).min(i16::MAX as f32) as i16
+    }
+}
+
+/// A low-pass sinc with a Blackman window `half_width` samples to each side
+fn windowed_sinc(x: f64, cutoff: f64, half_width: f64) -> f64 {
+    if x.abs() >= half_width {
+        return 0.0;
+    }
+    let sinc = if x == 0.0 {
+        2.0 * cutoff
+    } else {
+        (2.0 * PI * cutoff * x).sin() / (PI * x)
+    };
+    let t = (x / half_width + 1.0) / 2.0;
+    let window = 0.42 - 0.5 * (2.0 * PI * t).cos() + 0.08 * (4.0 * PI * t).cos();
+    sinc * window
+}
+
+struct Stream {
+    writer: WavWriter,
+    resamplers: Vec<Resampler>,
+    buffers: Vec<Vec<i16>>,
+    interleaved: Vec<i16>,
+}
+
+impl Stream {
+    fn new(path: &Path, channels: u16, rate: u32) -> io::Result<Self> {
+        Ok(Stream {
+            writer: WavWriter::create(path, channels, rate)?,
+            resamplers: (0..channels).map(|_| Resampler::new(rate)).collect(),
+            buffers: vec![Vec::new(); channels as usize],
+            interleaved: Vec::new(),
+        })
+    }
+
+    fn write(&mut self, samples: &[Sample], get: impl Fn(&Sample, usize) -> f32) -> io::Result<()> {
+        for (i, (resampler, buffer)) in self
+            .resamplers
+            .iter_mut()
+            .zip(self.buffers.iter_mut())
+            .enumerate()
+        {
+            buffer.clear();
+            resampler.process(samples.iter().map(|sample| get(sample, i)), buffer);
+        }
+
+        // Every channel is fed the same number of samples, so they all have the same length
+        self.interleaved.clear();
+        for idx in 0..self.buffers[0].len() {
+            self.interleaved
+                .extend(self.buffers.iter().map(|buffer| buffer[idx]));
+        }
+        self.writer.write(&self.interleaved)?;
+        self.writer.update_header()
+    }
+}
+
+/// Writes the mixed stereo output, and optionally each channel on its own
+pub struct WavRecorder {
+    mix: Stream,
+    stems: Vec<Stream>,
+}
+
+impl WavRecorder {
+    /// Stems are written next to `path`, with `.ch1.wav` to `.ch4.wav` in place of its extension
+    pub fn new<P: AsRef<Path>>(path: P, rate: u32, stems: bool) -> io::Result<Self> {
+        let path = path.as_ref();
+        let stems = if stems {
+            (1..=4)
+                .map(|ch| Stream::new(&path.with_extension(format!("ch{}.wav", ch)), 1, rate))
+                .collect::<io::Result<_>>()?
+        } else {
+            Vec::new()
+        };
+
+        Ok(WavRecorder {
+            mix: Stream::new(path, 2, rate)?,
+            stems,
+        })
+    }
+
+    pub fn record(&mut self, samples: &[Sample]) -> io::Result<()> {
+        self.mix.write(samples, |sample, side| match side {
+            0 => sample.left as f32,
+            _ => sample.right as f32,
+        })?;
+        for (ch, stem) in self.stems.iter_mut().enumerate() {
+            stem.write(samples, |sample, _| sample.channels[ch] as f32 * STEM_SCALE)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn resampler_keeps_level_and_rate() {
+        let mut resampler = Resampler::new(48000);
+        let mut out = Vec::new();
+        let input = apu::SAMPLE_RATE as usize;
+        resampler.process(std::iter::repeat(1000.0).take(input), &mut out);
+
+        // A second of input, less what's held back for the filter
+        assert!(out.len() <= 48000 && out.len() > 47900);
+        assert!(out[out.len() / 2..].iter().all(|x| (*x - 1000).abs() <= 1));
+    }
+
+    /// RMS level of the second half of a second of a sine at `freq`, relative to the input's
+    fn sine_level(freq: f64, rate: u32) -> f64 {
+        let mut resampler = Resampler::new(rate);
+        let mut out = Vec::new();
+        let input = (0..apu::SAMPLE_RATE).map(|i| {
+            let t = i as f64 / apu::SAMPLE_RATE as f64;
+            (10000.0 * (2.0 * PI * freq * t).sin()) as f32
+        });
+        resampler.process(input, &mut out);
+
+        let settled = &out[out.len() / 2..];
+        let power = settled.iter().map(|x| (*x as f64).powi(2)).sum::<f64>() / settled.len() as f64;
+        power.sqrt() / (10000.0 / 2f64.sqrt())
+    }
+
+    #[test]
+    fn resampler_removes_what_would_alias() {
+        assert!((sine_level(1000.0, 48000) - 1.0).abs() < 0.01);
+        // Above the output's Nyquist frequency, and just under the rate between the steps
+        assert!(sine_level(30000.0, 48000) < 1e-3);
+        assert!(sine_level(120000.0, 48000) < 1e-3);
+        assert!(sine_level(80000.0, 192000) < 1e-3);
+    }
+}
@@ -1,7 +1,5 @@
 #![allow(dead_code)]
 
-use crate::gb::devices::Ppu;
-
 use super::{Bus, DeviceWrapper};
 
 pub struct BusWrapper<'a> {
@@ -10,11 +8,8 @@ pub struct BusWrapper<'a> {
 }
 
 impl<'a> BusWrapper<'a> {
-    pub fn new(bus: &'a mut Bus, ppu: &'a mut Ppu) -> Self {
-        BusWrapper {
-            bus,
-            devices: DeviceWrapper { ppu },
-        }
+    pub fn new(bus: &'a mut Bus, devices: DeviceWrapper<'a>) -> Self {
+        BusWrapper { bus, devices }
     }
 
     pub fn read(&mut self, addr: u16) -> u8 {
@@ -0,0 +1,193 @@
+use crate::gb::state::{self, Reader, Snapshot};
+
+use super::super::{Kind, Module, PageStatus, Ram};
+
+const BANK_SIZE: usize = 0x2000;
+const PAGE_SIZE: u16 = 0x100;
+
+/// External RAM on the cartridge, split into the 8KiB banks that get mapped at 0xa000.  Each bank
+/// is a separate `Ram` so that pages keep their addresses in 0xa000-0xbfff.
+pub struct CartridgeRam {
+    banks: Vec<Ram>,
+    bank_size: usize,
+    /// Only the low 4 bits of each byte exist, the rest always read as 1s.  They're stored set,
+    /// so compiled code reading the pages directly sees the same values.
+    nibbles: bool,
+    /// Whether the contents have changed since they were last saved
+    dirty: bool,
+}
+
+impl CartridgeRam {
+    pub fn new(size: usize) -> Self {
+        Self::with_fill(size, false)
+    }
+
+    /// RAM that's only 4 bits wide, like MBC2's
+    pub fn new_nibbles(size: usize) -> Self {
+        Self::with_fill(size, true)
+    }
+
+    fn with_fill(size: usize, nibbles: bool) -> Self {
+        let bank_size = if size == 0 {
+            BANK_SIZE
+        } else {
+            size.min(BANK_SIZE)
+        };
+        let fill = if nibbles { 0xf0 } else { 0x00 };
+        let banks = (0..size / bank_size)
+            .map(|_| {
+                let data = vec![fill; bank_size];
+                Ram::new_with_data(data, Kind::Cram, 0xa000, PAGE_SIZE)
+            })
+            .collect();
+        CartridgeRam {
+            banks,
+            bank_size,
+            nibbles,
+            dirty: false,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.banks.len() * self.bank_size
+    }
+
+    pub fn save_state_pages(&self, out: &mut Vec<u8>, pages: &mut Vec<state::Page>) {
+        for bank in &self.banks {
+            bank.save_state_pages(out, pages);
+        }
+    }
+
+    fn locate(&mut self, offset: usize) -> (usize, &mut Ram, u16) {
+        let bank = offset / self.bank_size;
+        let addr = 0xa000 + (offset % self.bank_size) as u16;
+        (bank, &mut self.banks[bank], addr)
+    }
+
+    pub fn read(&mut self, offset: usize) -> u8 {
+        let (_, ram, addr) = self.locate(offset);
+        ram.read(addr)
+    }
+
+    pub fn write(&mut self, offset: usize, val: u8) {
+        self.dirty = true;
+        let val = self.mask(val);
+        let (_, ram, addr) = self.locate(offset);
+        ram.write(addr, val)
+    }
+
+    fn mask(&self, val: u8) -> u8 {
+        if self.nibbles {
+            val | 0xf0
+        } else {
+            val
+        }
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// The contents of every bank in order, which is the raw .sav format
+    pub fn contents(&mut self) -> Vec<u8> {
+        self.dirty = false;
+        self.banks
+            .iter()
+            .flat_map(|ram| ram.data())
+            .copied()
+            .collect()
+    }
+
+    pub fn load(&mut self, data: &[u8]) {
+        for (offset, val) in data.iter().enumerate().take(self.size()) {
+            let val = self.mask(*val);
+            let (_, ram, addr) = self.locate(offset);
+            ram.write(addr, val);
+        }
+        self.dirty = false;
+    }
+
+    /// Map the page holding `offset` for code running at `addr`, which is a mirror of it when
+    /// the RAM is smaller than 0xa000-0xbfff.  Each mirror is kept apart, since the same code
+    /// compiles differently at a different address.
+    pub fn map_page(&mut self, offset: usize, addr: u16) -> (PageStatus, &[u8]) {
+        let mirror = (addr as usize - 0xa000) / self.bank_size;
+        let (bank, ram, bank_addr) = self.locate(offset);
+        let (mut ps, data) = ram.map_page(bank_addr);
+        ps.id.1 |= (bank as u64) << 16 | (mirror as u64) << 24;
+        ps.base_addr = addr & !(PAGE_SIZE - 1);
+        (ps, data)
+    }
+}
+
+impl Snapshot for CartridgeRam {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        self.save_state_pages(out, &mut Vec::new())
+    }
+
+    /// Loading doesn't count as a change, the save file is only written over once the game
+    /// writes to the RAM itself
+    fn load_state(&mut self, r: &mut Reader<'_>) -> Result<(), state::Error> {
+        for bank in &mut self.banks {
+            bank.load_state(r)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn contents_round_trip() {
+        let mut ram = CartridgeRam::new(0x8000);
+        assert!(!ram.is_dirty());
+        ram.write(0x0000, 0x12);
+        ram.write(0x2001, 0x34);
+        ram.write(0x7fff, 0x56);
+        assert!(ram.is_dirty());
+
+        let data = ram.contents();
+        assert!(!ram.is_dirty());
+        assert_eq!(data.len(), 0x8000);
+        assert_eq!(
+            (data[0x0000], data[0x2001], data[0x7fff]),
+            (0x12, 0x34, 0x56)
+        );
+
+        let mut loaded = CartridgeRam::new(0x8000);
+        loaded.load(&data);
+        assert_eq!(loaded.read(0x2001), 0x34);
+        assert_eq!(loaded.contents(), data);
+    }
+
+    #[test]
+    fn state_load_leaves_ram_clean() {
+        let mut ram = CartridgeRam::new(0x2000);
+        ram.write(0x0010, 0x12);
+        let mut out = Vec::new();
+        ram.save_state(&mut out);
+
+        let mut loaded = CartridgeRam::new(0x2000);
+        loaded.load_state(&mut Reader::new(&out)).unwrap();
+        assert_eq!(loaded.read(0x0010), 0x12);
+        assert!(!loaded.is_dirty());
+    }
+
+    #[test]
+    fn small_ram_pages_follow_mirrors() {
+        let mut ram = CartridgeRam::new_nibbles(0x200);
+        ram.write(0x123, 0x05);
+        assert_eq!(ram.read(0x123), 0xf5);
+
+        let (ps, data) = ram.map_page(0x100, 0xa900);
+        assert_eq!((ps.base_addr, ps.size), (0xa900, 0x100));
+        // Compiled code sees the same value as the bus
+        assert_eq!(data[0x23], 0xf5);
+        assert_eq!(data[0x24], 0xf0);
+        let (mirror, _) = ram.map_page(0x100, 0xa100);
+        assert_eq!(mirror.base_addr, 0xa100);
+        assert_ne!(mirror.id, ps.id);
+    }
+}
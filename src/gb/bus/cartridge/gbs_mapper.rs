@@ -0,0 +1,37 @@
+use crate::gb::state::impl_snapshot;
+
+use super::{Mbc, RamAccess};
+
+/// The banking GBS players provide, which isn't a real MBC.  Any write to 0x2000-0x3fff selects
+/// the bank at 0x4000-0x7fff, and 8KiB of RAM is always there without having to be enabled.
+pub struct GbsMapper {
+    rom_bank: u8,
+}
+
+impl GbsMapper {
+    pub fn new() -> Self {
+        GbsMapper { rom_bank: 1 }
+    }
+}
+
+impl_snapshot!(GbsMapper { rom_bank });
+
+impl Mbc for GbsMapper {
+    fn write_reg(&mut self, addr: u16, val: u8) {
+        if let 0x2000..=0x3fff = addr {
+            // Bank 0 can't be mapped there, as on the MBC1, and selects bank 1 instead
+            self.rom_bank = val.max(1);
+        }
+    }
+
+    fn rom_bank(&self, region: u16) -> usize {
+        match region {
+            0 => 0,
+            _ => self.rom_bank as usize,
+        }
+    }
+
+    fn ram_access(&self, addr: u16) -> RamAccess {
+        RamAccess::Ram(addr as usize - 0xa000)
+    }
+}
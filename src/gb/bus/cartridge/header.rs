@@ -0,0 +1,241 @@
+use std::fmt;
+
+use log::*;
+
+use super::Error;
+
+/// The header occupies 0x100-0x14f, so any valid ROM is at least this long
+const HEADER_END: usize = 0x150;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum MbcKind {
+    None,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+}
+
+/// The hardware on the cartridge, decoded from the cartridge type byte at 0x147
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct CartridgeType {
+    pub mbc: MbcKind,
+    pub ram: bool,
+    pub battery: bool,
+    pub timer: bool,
+    pub rumble: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Licensee {
+    Old(u8),
+    New([u8; 2]),
+}
+
+#[derive(Debug, Clone)]
+pub struct CartridgeHeader {
+    pub title: String,
+    pub cgb_flag: u8,
+    pub sgb_flag: u8,
+    pub cartridge_type: CartridgeType,
+    pub rom_size: usize,
+    pub ram_size: usize,
+    pub licensee: Licensee,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+}
+
+impl CartridgeType {
+    fn from_byte(val: u8) -> Option<Self> {
+        use MbcKind::*;
+        let (mbc, ram, battery, timer, rumble) = match val {
+            0x00 => (None, false, false, false, false),
+            0x01 => (Mbc1, false, false, false, false),
+            0x02 => (Mbc1, true, false, false, false),
+            0x03 => (Mbc1, true, true, false, false),
+            0x05 => (Mbc2, false, false, false, false),
+            0x06 => (Mbc2, false, true, false, false),
+            0x08 => (None, true, false, false, false),
+            0x09 => (None, true, true, false, false),
+            0x0f => (Mbc3, false, true, true, false),
+            0x10 => (Mbc3, true, true, true, false),
+            0x11 => (Mbc3, false, false, false, false),
+            0x12 => (Mbc3, true, false, false, false),
+            0x13 => (Mbc3, true, true, false, false),
+            0x19 => (Mbc5, false, false, false, false),
+            0x1a => (Mbc5, true, false, false, false),
+            0x1b => (Mbc5, true, true, false, false),
+            0x1c => (Mbc5, false, false, false, true),
+            0x1d => (Mbc5, true, false, false, true),
+            0x1e => (Mbc5, true, true, false, true),
+            _ => return Option::None,
+        };
+        Some(CartridgeType {
+            mbc,
+            ram,
+            battery,
+            timer,
+            rumble,
+        })
+    }
+}
+
+impl CartridgeHeader {
+    pub fn parse(rom: &[u8]) -> Result<Self, Error> {
+        if rom.len() < HEADER_END {
+            return Err(Error::Truncated(rom.len()));
+        }
+
+        let title_bytes = &rom[0x134..0x143];
+        let title = title_bytes
+            .iter()
+            .take_while(|b| **b != 0)
+            .map(|b| *b as char)
+            .collect();
+
+        let cartridge_type =
+            CartridgeType::from_byte(rom[0x147]).ok_or(Error::UnknownMapper(rom[0x147]))?;
+
+        let rom_size = match rom[0x148] {
+            n @ 0x00..=0x08 => 0x8000 << n,
+            n => return Err(Error::UnknownRomSize(n)),
+        };
+        let ram_size = match rom[0x149] {
+            0x00 => 0,
+            0x01 => 0x800,
+            0x02 => 0x2000,
+            0x03 => 0x8000,
+            0x04 => 0x20000,
+            0x05 => 0x10000,
+            n => return Err(Error::UnknownRamSize(n)),
+        };
+
+        let licensee = match rom[0x14b] {
+            0x33 => Licensee::New([rom[0x144], rom[0x145]]),
+            code => Licensee::Old(code),
+        };
+
+        let header = CartridgeHeader {
+            title,
+            cgb_flag: rom[0x143],
+            sgb_flag: rom[0x146],
+            cartridge_type,
+            rom_size,
+            ram_size,
+            licensee,
+            header_checksum: rom[0x14d],
+            global_checksum: u16::from_be_bytes([rom[0x14e], rom[0x14f]]),
+        };
+
+        if rom.len() < rom_size {
+            return Err(Error::SizeMismatch(rom_size, rom.len()));
+        } else if rom.len() > rom_size {
+            warn!(
+                "ROM is {:#x} bytes but the header declares {:#x}, ignoring the rest",
+                rom.len(),
+                rom_size
+            );
+        }
+
+        let checksum = compute_header_checksum(rom);
+        if checksum != header.header_checksum {
+            warn!(
+                "Header checksum mismatch, computed {:#04x}, header has {:#04x}",
+                checksum, header.header_checksum
+            );
+        }
+
+        Ok(header)
+    }
+
+    /// The size of the cartridge RAM, MBC2 has RAM built in regardless of what the header says
+    pub fn effective_ram_size(&self) -> usize {
+        match self.cartridge_type.mbc {
+            MbcKind::Mbc2 => 0x200,
+            _ => self.ram_size,
+        }
+    }
+}
+
+pub(super) fn compute_header_checksum(rom: &[u8]) -> u8 {
+    rom[0x134..0x14d]
+        .iter()
+        .fold(0u8, |acc, b| acc.wrapping_sub(*b).wrapping_sub(1))
+}
+
+impl fmt::Display for CartridgeHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:?} ({:?}), ROM {}KiB, RAM {}KiB, CGB {:#04x}, SGB {:#04x}, licensee {}, \
+             checksums {:#04x}/{:#06x}",
+            self.title,
+            self.cartridge_type,
+            self.rom_size / 1024,
+            self.ram_size / 1024,
+            self.cgb_flag,
+            self.sgb_flag,
+            self.licensee,
+            self.header_checksum,
+            self.global_checksum,
+        )
+    }
+}
+
+impl fmt::Display for Licensee {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Licensee::Old(code) => write!(f, "{:#04x}", code),
+            Licensee::New(code) => write!(f, "{:?}", String::from_utf8_lossy(code)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn make_rom(cartridge_type: u8, rom_size: u8, ram_size: u8, len: usize) -> Vec<u8> {
+        let mut rom = vec![0u8; len.max(HEADER_END)];
+        rom[0x134..0x139].copy_from_slice(b"TESTS");
+        rom[0x147] = cartridge_type;
+        rom[0x148] = rom_size;
+        rom[0x149] = ram_size;
+        rom[0x14d] = compute_header_checksum(&rom);
+        rom.truncate(len);
+        rom
+    }
+
+    #[test]
+    fn parse_header() {
+        let rom = make_rom(0x1b, 0x02, 0x03, 0x20000);
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.title, "TESTS");
+        assert_eq!(header.cartridge_type.mbc, MbcKind::Mbc5);
+        assert!(header.cartridge_type.battery);
+        assert_eq!(header.rom_size, 0x20000);
+        assert_eq!(header.ram_size, 0x8000);
+        assert_eq!(header.licensee, Licensee::Old(0));
+    }
+
+    #[test]
+    fn malformed_headers() {
+        let truncated = make_rom(0x00, 0x00, 0x00, 0x100);
+        match CartridgeHeader::parse(&truncated) {
+            Err(Error::Truncated(0x100)) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        let short = make_rom(0x01, 0x02, 0x00, 0x8000);
+        match CartridgeHeader::parse(&short) {
+            Err(Error::SizeMismatch(0x20000, 0x8000)) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        let unknown = make_rom(0xfc, 0x00, 0x00, 0x8000);
+        match CartridgeHeader::parse(&unknown) {
+            Err(Error::UnknownMapper(0xfc)) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use crate::gb::state::impl_snapshot;
+
+use super::{Mbc, RamAccess};
+
+/// MBC1, supporting up to 2MiB of ROM and 32KiB of RAM.  The multicart variant (MBC1M) has the
+/// same registers, but only wires 4 bits of the low bank register to the ROM.
+pub struct Mbc1 {
+    multicart: bool,
+    ram_enabled: bool,
+    bank1: u8,
+    bank2: u8,
+    mode: bool,
+}
+
+impl Mbc1 {
+    pub fn new(multicart: bool) -> Self {
+        Mbc1 {
+            multicart,
+            ram_enabled: false,
+            bank1: 1,
+            bank2: 0,
+            mode: false,
+        }
+    }
+
+    fn bank2_shift(&self) -> u32 {
+        if self.multicart {
+            4
+        } else {
+            5
+        }
+    }
+}
+
+impl_snapshot!(Mbc1 {
+    ram_enabled,
+    bank1,
+    bank2,
+    mode
+});
+
+impl Mbc for Mbc1 {
+    fn write_reg(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x1fff => self.ram_enabled = val & 0xf == 0xa,
+            0x2000..=0x3fff => {
+                // The zero check applies to all 5 bits, even when fewer are wired
+                self.bank1 = if val & 0x1f == 0 { 1 } else { val & 0x1f };
+            }
+            0x4000..=0x5fff => self.bank2 = val & 0x3,
+            0x6000..=0x7fff => self.mode = val & 0x1 != 0,
+            _ => unreachable!(),
+        }
+    }
+
+    fn rom_bank(&self, region: u16) -> usize {
+        let high = (self.bank2 as usize) << self.bank2_shift();
+        match region {
+            0 if self.mode => high,
+            0 => 0,
+            _ => {
+                let low_mask = (1 << self.bank2_shift()) - 1;
+                high | (self.bank1 as usize & low_mask)
+            }
+        }
+    }
+
+    fn ram_access(&self, addr: u16) -> RamAccess {
+        if !self.ram_enabled {
+            return RamAccess::Disabled;
+        }
+        let bank = if self.mode { self.bank2 as usize } else { 0 };
+        RamAccess::Ram(bank * 0x2000 + (addr as usize - 0xa000))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rom_banking() {
+        let mut mbc = Mbc1::new(false);
+        assert_eq!((mbc.rom_bank(0), mbc.rom_bank(1)), (0, 1));
+        mbc.write_reg(0x2000, 0x00);
+        assert_eq!(mbc.rom_bank(1), 1);
+        mbc.write_reg(0x2000, 0x12);
+        mbc.write_reg(0x4000, 0x02);
+        assert_eq!((mbc.rom_bank(0), mbc.rom_bank(1)), (0, 0x52));
+        mbc.write_reg(0x6000, 0x01);
+        assert_eq!((mbc.rom_bank(0), mbc.rom_bank(1)), (0x40, 0x52));
+        mbc.write_reg(0x2000, 0x20);
+        assert_eq!(mbc.rom_bank(1), 0x41);
+    }
+
+    #[test]
+    fn multicart_rom_banking() {
+        let mut mbc = Mbc1::new(true);
+        mbc.write_reg(0x2000, 0x13);
+        mbc.write_reg(0x4000, 0x01);
+        assert_eq!((mbc.rom_bank(0), mbc.rom_bank(1)), (0, 0x13));
+        mbc.write_reg(0x6000, 0x01);
+        assert_eq!((mbc.rom_bank(0), mbc.rom_bank(1)), (0x10, 0x13));
+    }
+
+    #[test]
+    fn ram_banking() {
+        let mut mbc = Mbc1::new(false);
+        assert_eq!(mbc.ram_access(0xa000), RamAccess::Disabled);
+        mbc.write_reg(0x0000, 0x0a);
+        mbc.write_reg(0x4000, 0x03);
+        assert_eq!(mbc.ram_access(0xa123), RamAccess::Ram(0x123));
+        mbc.write_reg(0x6000, 0x01);
+        assert_eq!(mbc.ram_access(0xa123), RamAccess::Ram(0x6123));
+        mbc.write_reg(0x0000, 0x00);
+        assert_eq!(mbc.ram_access(0xa123), RamAccess::Disabled);
+    }
+}
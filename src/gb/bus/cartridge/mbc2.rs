@@ -0,0 +1,83 @@
+use crate::gb::state::impl_snapshot;
+
+use super::{Mbc, RamAccess};
+
+/// MBC2, supporting up to 256KiB of ROM with 512 half-bytes of RAM built into the controller.
+/// Both registers live in 0x0000-0x3fff and are told apart by bit 8 of the address.
+pub struct Mbc2 {
+    ram_enabled: bool,
+    rom_bank: u8,
+}
+
+impl Mbc2 {
+    pub fn new() -> Self {
+        Mbc2 {
+            ram_enabled: false,
+            rom_bank: 1,
+        }
+    }
+}
+
+impl_snapshot!(Mbc2 {
+    ram_enabled,
+    rom_bank
+});
+
+impl Mbc for Mbc2 {
+    fn write_reg(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x3fff if addr & 0x100 == 0 => self.ram_enabled = val & 0xf == 0xa,
+            0x0000..=0x3fff => self.rom_bank = if val & 0xf == 0 { 1 } else { val & 0xf },
+            _ => (),
+        }
+    }
+
+    fn rom_bank(&self, region: u16) -> usize {
+        match region {
+            0 => 0,
+            _ => self.rom_bank as usize,
+        }
+    }
+
+    fn ram_access(&self, addr: u16) -> RamAccess {
+        if !self.ram_enabled {
+            return RamAccess::Disabled;
+        }
+        // The 512 entries are mirrored throughout 0xa000-0xbfff
+        RamAccess::Ram(addr as usize & 0x1ff)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn address_bit_8_picks_register() {
+        let mut mbc = Mbc2::new();
+        mbc.write_reg(0x2100, 0x0a);
+        assert_eq!(mbc.ram_access(0xa000), RamAccess::Disabled);
+        assert_eq!(mbc.rom_bank(1), 0x0a);
+        mbc.write_reg(0x0000, 0x0a);
+        assert_eq!(mbc.ram_access(0xa000), RamAccess::Ram(0));
+        assert_eq!(mbc.rom_bank(1), 0x0a);
+    }
+
+    #[test]
+    fn rom_bank_0_maps_to_1() {
+        let mut mbc = Mbc2::new();
+        mbc.write_reg(0x0100, 0x05);
+        mbc.write_reg(0x0100, 0xf0);
+        assert_eq!(mbc.rom_bank(0), 0);
+        assert_eq!(mbc.rom_bank(1), 1);
+    }
+
+    #[test]
+    fn ram_echoes_every_512_bytes() {
+        let mut mbc = Mbc2::new();
+        mbc.write_reg(0x0000, 0x0a);
+        assert_eq!(mbc.ram_access(0xa123), RamAccess::Ram(0x123));
+        assert_eq!(mbc.ram_access(0xa323), RamAccess::Ram(0x123));
+        assert_eq!(mbc.ram_access(0xbf23), RamAccess::Ram(0x123));
+    }
+}
@@ -0,0 +1,101 @@
+use crate::gb::state::{self, Reader, Snapshot};
+
+use super::rtc::Rtc;
+use super::{Mbc, RamAccess};
+
+/// MBC3, supporting up to 2MiB of ROM, 32KiB of RAM and an optional real-time clock whose
+/// registers are selected through the RAM bank register.
+pub struct Mbc3 {
+    rtc: Option<Rtc>,
+    ram_enabled: bool,
+    rom_bank: u8,
+    ram_bank: u8,
+}
+
+impl Mbc3 {
+    pub fn new(rtc: Option<Rtc>) -> Self {
+        Mbc3 {
+            rtc,
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+        }
+    }
+
+    fn rtc_selected(&self) -> bool {
+        self.rtc.is_some() && (0x08..=0x0c).contains(&self.ram_bank)
+    }
+}
+
+impl Snapshot for Mbc3 {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        self.ram_enabled.save_state(out);
+        self.rom_bank.save_state(out);
+        self.ram_bank.save_state(out);
+        // Whether there's a clock depends on the cartridge, not the state
+        if let Some(rtc) = &self.rtc {
+            rtc.save_state(out);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut Reader<'_>) -> Result<(), state::Error> {
+        self.ram_enabled.load_state(r)?;
+        self.rom_bank.load_state(r)?;
+        self.ram_bank.load_state(r)?;
+        if let Some(rtc) = &mut self.rtc {
+            rtc.load_state(r)?;
+        }
+        Ok(())
+    }
+}
+
+impl Mbc for Mbc3 {
+    fn write_reg(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x1fff => self.ram_enabled = val & 0xf == 0xa,
+            0x2000..=0x3fff => self.rom_bank = if val & 0x7f == 0 { 1 } else { val & 0x7f },
+            0x4000..=0x5fff => self.ram_bank = val & 0xf,
+            0x6000..=0x7fff => {
+                if let Some(rtc) = self.rtc.as_mut() {
+                    rtc.write_latch(val)
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn rom_bank(&self, region: u16) -> usize {
+        match region {
+            0 => 0,
+            _ => self.rom_bank as usize,
+        }
+    }
+
+    fn ram_access(&self, addr: u16) -> RamAccess {
+        if !self.ram_enabled {
+            RamAccess::Disabled
+        } else if self.rtc_selected() {
+            RamAccess::Register
+        } else if self.ram_bank < 4 {
+            RamAccess::Ram(self.ram_bank as usize * 0x2000 + (addr as usize - 0xa000))
+        } else {
+            RamAccess::Disabled
+        }
+    }
+
+    fn read_ram_reg(&mut self, _addr: u16) -> u8 {
+        let reg = self.ram_bank;
+        self.rtc.as_ref().map_or(0xff, |rtc| rtc.read(reg))
+    }
+
+    fn write_ram_reg(&mut self, _addr: u16, val: u8) {
+        let reg = self.ram_bank;
+        if let Some(rtc) = self.rtc.as_mut() {
+            rtc.write(reg, val)
+        }
+    }
+
+    fn rtc(&mut self) -> Option<&mut Rtc> {
+        self.rtc.as_mut()
+    }
+}
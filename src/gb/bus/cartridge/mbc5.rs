@@ -0,0 +1,94 @@
+use crate::gb::state::impl_snapshot;
+
+use super::{Mbc, RamAccess};
+
+/// MBC5, supporting up to 8MiB of ROM through a 9 bit bank number and 128KiB of RAM.  On rumble
+/// cartridges bit 3 of the RAM bank register drives the motor instead.
+pub struct Mbc5 {
+    has_rumble: bool,
+    ram_enabled: bool,
+    rom_bank: u16,
+    ram_bank: u8,
+    rumble: bool,
+}
+
+impl Mbc5 {
+    pub fn new(has_rumble: bool) -> Self {
+        Mbc5 {
+            has_rumble,
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            rumble: false,
+        }
+    }
+}
+
+impl_snapshot!(Mbc5 {
+    ram_enabled,
+    rom_bank,
+    ram_bank,
+    rumble
+});
+
+impl Mbc for Mbc5 {
+    fn write_reg(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x1fff => self.ram_enabled = val & 0xf == 0xa,
+            0x2000..=0x2fff => self.rom_bank = (self.rom_bank & 0x100) | val as u16,
+            0x3000..=0x3fff => self.rom_bank = (self.rom_bank & 0xff) | (val as u16 & 1) << 8,
+            0x4000..=0x5fff => {
+                if self.has_rumble {
+                    self.rumble = val & 0x8 != 0;
+                    self.ram_bank = val & 0x7;
+                } else {
+                    self.ram_bank = val & 0xf;
+                }
+            }
+            0x6000..=0x7fff => (),
+            _ => unreachable!(),
+        }
+    }
+
+    fn rom_bank(&self, region: u16) -> usize {
+        match region {
+            0 => 0,
+            _ => self.rom_bank as usize,
+        }
+    }
+
+    fn ram_access(&self, addr: u16) -> RamAccess {
+        if !self.ram_enabled {
+            return RamAccess::Disabled;
+        }
+        RamAccess::Ram(self.ram_bank as usize * 0x2000 + (addr as usize - 0xa000))
+    }
+
+    fn rumble(&self) -> bool {
+        self.rumble
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn nine_bit_rom_bank() {
+        let mut mbc = Mbc5::new(false);
+        mbc.write_reg(0x2000, 0x00);
+        assert_eq!(mbc.rom_bank(1), 0);
+        mbc.write_reg(0x3000, 0x01);
+        mbc.write_reg(0x2000, 0x23);
+        assert_eq!(mbc.rom_bank(1), 0x123);
+    }
+
+    #[test]
+    fn rumble_uses_ram_bank_bit() {
+        let mut mbc = Mbc5::new(true);
+        mbc.write_reg(0x0000, 0x0a);
+        mbc.write_reg(0x4000, 0x0b);
+        assert!(mbc.rumble());
+        assert_eq!(mbc.ram_access(0xa000), RamAccess::Ram(0x6000));
+    }
+}
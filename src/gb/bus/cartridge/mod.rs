@@ -0,0 +1,391 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::*;
+use quick_error::quick_error;
+
+use crate::compiler::CycleState;
+use crate::gb::state::{self, impl_snapshot, Reader, Snapshot};
+use crate::gb::GbOptions;
+
+use super::Error as BusError;
+use super::{Kind, Module, PageStatus, Rom};
+
+mod cart_ram;
+mod gbs_mapper;
+mod header;
+mod mbc1;
+mod mbc2;
+mod mbc3;
+mod mbc5;
+mod rtc;
+
+use cart_ram::CartridgeRam;
+use gbs_mapper::GbsMapper;
+pub use header::CartridgeHeader;
+use header::MbcKind;
+use mbc1::Mbc1;
+use mbc2::Mbc2;
+use mbc3::Mbc3;
+use mbc5::Mbc5;
+use rtc::{ClockSource, Rtc};
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        Truncated(len: usize) {
+            display("ROM is only {:#x} bytes, too short to contain a header", len)
+        }
+        SizeMismatch(expected: usize, actual: usize) {
+            display("Header declares {:#x} bytes of ROM but the file is {:#x} bytes", expected, actual)
+        }
+        UnknownMapper(val: u8) {
+            display("Unknown cartridge type {:#04x}", val)
+        }
+        UnknownRomSize(val: u8) {
+            display("Unknown ROM size {:#04x}", val)
+        }
+        UnknownRamSize(val: u8) {
+            display("Unknown RAM size {:#04x}", val)
+        }
+    }
+}
+
+const ROM_BANK_SIZE: usize = 0x4000;
+
+/// RAM always available to GBS files, which they're free to use from 0xa000
+const GBS_RAM_SIZE: usize = 0x2000;
+
+/// Size of the pages handed out for disabled RAM
+const RAM_PAGE_SIZE: u16 = 0x100;
+
+static DISABLED_RAM: [u8; RAM_PAGE_SIZE as usize] = [0xff; RAM_PAGE_SIZE as usize];
+
+/// What an address in 0xa000-0xbfff maps to
+#[derive(Debug, PartialEq, Eq)]
+enum RamAccess {
+    Disabled,
+    /// An offset into cartridge RAM, masked to the size of the RAM by the caller
+    Ram(usize),
+    /// A register in the MBC itself, accessed through `read_ram_reg`/`write_ram_reg`
+    Register,
+}
+
+/// The memory bank controller, which decides which parts of the ROM and RAM are visible.
+trait Mbc: Snapshot {
+    /// Handle a write to the control registers in 0x0000-0x7fff
+    fn write_reg(&mut self, addr: u16, val: u8);
+
+    /// The ROM bank mapped at a region, 0 for 0x0000-0x3fff and 1 for 0x4000-0x7fff.  This is
+    /// masked to the size of the ROM by the caller.
+    fn rom_bank(&self, region: u16) -> usize;
+
+    fn ram_access(&self, addr: u16) -> RamAccess;
+
+    fn read_ram_reg(&mut self, _addr: u16) -> u8 {
+        0xff
+    }
+
+    fn write_ram_reg(&mut self, _addr: u16, _val: u8) {}
+
+    /// Whether the rumble motor is currently on
+    fn rumble(&self) -> bool {
+        false
+    }
+
+    /// The real-time clock, which is saved along with battery-backed RAM
+    fn rtc(&mut self) -> Option<&mut Rtc> {
+        None
+    }
+}
+
+pub type RumbleCallback = Box<dyn FnMut(bool)>;
+
+/// Cartridges with no MBC, at most 32KiB of ROM and 8KiB of RAM.
+struct NoMbc;
+
+impl_snapshot!(NoMbc {});
+
+impl Mbc for NoMbc {
+    fn write_reg(&mut self, addr: u16, val: u8) {
+        warn!(
+            "Attempted to write to Cartridge {:#06x?} <- {:02x?}",
+            addr, val
+        );
+    }
+
+    fn rom_bank(&self, region: u16) -> usize {
+        region as usize
+    }
+
+    fn ram_access(&self, addr: u16) -> RamAccess {
+        RamAccess::Ram(addr as usize - 0xa000)
+    }
+}
+
+pub struct Cartridge {
+    header: CartridgeHeader,
+    rom: Rom,
+    /// Identifies the ROM in save states
+    rom_checksum: u64,
+    ram: CartridgeRam,
+    mbc: Box<dyn Mbc>,
+    rumble_callback: Option<RumbleCallback>,
+    /// Where the RAM is persisted, only set for cartridges with a battery
+    save_path: Option<PathBuf>,
+}
+
+impl Cartridge {
+    pub fn new<P: AsRef<Path>>(
+        path: P,
+        cycles: Rc<CycleState>,
+        options: &GbOptions,
+    ) -> Result<Self, BusError> {
+        let rom = Rom::new(&path)?;
+        let rtc = || {
+            Some(Rtc::new(if options.rtc_host_clock {
+                ClockSource::Host
+            } else {
+                ClockSource::Cycles(cycles.clone())
+            }))
+        };
+        let header = CartridgeHeader::parse(&rom)?;
+        info!("Loaded cartridge {}", header);
+
+        let ty = header.cartridge_type;
+        let mut mbc: Box<dyn Mbc> = match ty.mbc {
+            MbcKind::None => Box::new(NoMbc),
+            MbcKind::Mbc1 => Box::new(Mbc1::new(is_mbc1_multicart(&rom))),
+            MbcKind::Mbc2 => Box::new(Mbc2::new()),
+            MbcKind::Mbc3 => Box::new(Mbc3::new(if ty.timer { rtc() } else { None })),
+            MbcKind::Mbc5 => Box::new(Mbc5::new(ty.rumble)),
+        };
+        let mut ram = match ty.mbc {
+            MbcKind::Mbc2 => CartridgeRam::new_nibbles(header.effective_ram_size()),
+            _ => CartridgeRam::new(header.effective_ram_size()),
+        };
+
+        // MBC3 cartridges can have a battery just for the clock, with no RAM
+        let has_saved_state = ram.size() != 0 || mbc.rtc().is_some();
+        let save_path = if ty.battery && has_saved_state && !options.no_save_file {
+            let save_path = options
+                .save_path
+                .clone()
+                .unwrap_or_else(|| path.as_ref().with_extension("sav"));
+            match fs::read(&save_path) {
+                Ok(data) => {
+                    let (ram_data, footer) = data.split_at(data.len().min(ram.size()));
+                    let footer_loaded = match mbc.rtc() {
+                        Some(rtc) => rtc.load_footer(footer, unix_time()),
+                        None => false,
+                    };
+                    if data.len() != ram.size() && !footer_loaded {
+                        warn!(
+                            "Save file {} is {:#x} bytes, expected {:#x}",
+                            save_path.display(),
+                            data.len(),
+                            ram.size()
+                        );
+                    }
+                    info!("Loaded save file {}", save_path.display());
+                    ram.load(ram_data);
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+                Err(err) => return Err(err.into()),
+            }
+            Some(save_path)
+        } else {
+            None
+        };
+
+        Ok(Cartridge {
+            header,
+            rom_checksum: state::checksum(&rom),
+            rom,
+            ram,
+            mbc,
+            rumble_callback: None,
+            save_path,
+        })
+    }
+
+    /// A cartridge built around a GBS file's ROM image, which has to be a power of two of at least
+    /// 32KiB.  GBS files have no cartridge header, so one is filled in to describe the image.
+    pub fn new_gbs(mut rom: Vec<u8>, title: &str) -> Result<Self, BusError> {
+        let title = title.bytes().map(|b| if b.is_ascii() { b } else { b'?' });
+        for (dst, src) in rom[0x134..0x143]
+            .iter_mut()
+            .zip(title.chain(std::iter::repeat(0)))
+        {
+            *dst = src;
+        }
+        // The closest real cartridge, an MBC5 with RAM and no battery
+        rom[0x147] = 0x1a;
+        rom[0x148] = (rom.len() / 0x8000).trailing_zeros() as u8;
+        rom[0x149] = 0x02;
+        rom[0x14d] = header::compute_header_checksum(&rom);
+
+        let rom = Rom::from_data(rom);
+        let header = CartridgeHeader::parse(&rom)?;
+        info!("Loaded GBS file as cartridge {}", header);
+
+        Ok(Cartridge {
+            header,
+            rom_checksum: state::checksum(&rom),
+            rom,
+            ram: CartridgeRam::new(GBS_RAM_SIZE),
+            mbc: Box::new(GbsMapper::new()),
+            rumble_callback: None,
+            save_path: None,
+        })
+    }
+
+    /// Write battery-backed RAM out to the save file if it has changed, or always for cartridges
+    /// with a real-time clock since it's always moving.  The file is the contents of the RAM
+    /// followed by the clock's footer, like most other emulators use.
+    pub fn save(&mut self) -> io::Result<()> {
+        let path = match self.save_path.as_ref() {
+            Some(path) if self.ram.is_dirty() || self.mbc.rtc().is_some() => path,
+            _ => return Ok(()),
+        };
+        let mut data = self.ram.contents();
+        if let Some(rtc) = self.mbc.rtc() {
+            data.extend(rtc.save_footer(unix_time()));
+        }
+        // Write to a temporary file first so a crash mid-write can't lose the old save
+        let tmp_path = path.with_extension("sav.tmp");
+        fs::write(&tmp_path, data)?;
+        fs::rename(&tmp_path, path)?;
+        debug!("Wrote save file {}", path.display());
+        Ok(())
+    }
+
+    pub fn set_rumble_callback(&mut self, callback: RumbleCallback) {
+        self.rumble_callback = Some(callback);
+    }
+
+    pub fn header(&self) -> &CartridgeHeader {
+        &self.header
+    }
+
+    pub fn rom_checksum(&self) -> u64 {
+        self.rom_checksum
+    }
+
+    pub fn save_state_pages(&self, out: &mut Vec<u8>, pages: &mut Vec<state::Page>) {
+        self.ram.save_state_pages(out, pages);
+        self.mbc.save_state(out);
+    }
+
+    fn rom_bank(&self, region: u16) -> usize {
+        let banks = (self.rom.len() / ROM_BANK_SIZE).max(1);
+        self.mbc.rom_bank(region) % banks
+    }
+
+    fn ram_access(&self, addr: u16) -> RamAccess {
+        match self.mbc.ram_access(addr) {
+            RamAccess::Ram(_) if self.ram.size() == 0 => RamAccess::Disabled,
+            RamAccess::Ram(offset) => RamAccess::Ram(offset % self.ram.size()),
+            access => access,
+        }
+    }
+}
+
+impl Snapshot for Cartridge {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        self.save_state_pages(out, &mut Vec::new())
+    }
+
+    fn load_state(&mut self, r: &mut Reader<'_>) -> Result<(), state::Error> {
+        self.ram.load_state(r)?;
+        self.mbc.load_state(r)
+    }
+}
+
+/// Seconds since the UNIX epoch, which .sav files timestamp the clock with
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+/// MBC1 multicarts are 1MiB and contain a copy of the Nintendo logo in the header of every game,
+/// the second of which starts at bank 0x10.
+fn is_mbc1_multicart(rom: &[u8]) -> bool {
+    const LOGO: std::ops::Range<usize> = 0x104..0x134;
+    const SECOND_HEADER: usize = 0x10 * ROM_BANK_SIZE;
+    rom.len() == 0x100000 && rom[LOGO] == rom[SECOND_HEADER + LOGO.start..SECOND_HEADER + LOGO.end]
+}
+
+impl Module for Cartridge {
+    fn read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x7fff => {
+                let bank = self.rom_bank(addr / 0x4000);
+                self.rom[bank * ROM_BANK_SIZE + (addr as usize & 0x3fff)]
+            }
+            _ => match self.ram_access(addr) {
+                RamAccess::Disabled => 0xff,
+                RamAccess::Ram(offset) => self.ram.read(offset),
+                RamAccess::Register => self.mbc.read_ram_reg(addr),
+            },
+        }
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x7fff => {
+                let rumble = self.mbc.rumble();
+                self.mbc.write_reg(addr, val);
+                if self.mbc.rumble() != rumble {
+                    if let Some(callback) = self.rumble_callback.as_mut() {
+                        callback(!rumble)
+                    }
+                }
+            }
+            _ => match self.ram_access(addr) {
+                RamAccess::Disabled => (),
+                RamAccess::Ram(offset) => self.ram.write(offset, val),
+                RamAccess::Register => self.mbc.write_ram_reg(addr, val),
+            },
+        }
+    }
+
+    fn map_page(&mut self, addr: u16) -> (PageStatus, &[u8]) {
+        match addr {
+            0x0000..=0x7fff => {
+                let region = addr / 0x4000;
+                let bank = self.rom_bank(region);
+                let base = bank * ROM_BANK_SIZE;
+                (
+                    PageStatus {
+                        // The same bank can be mapped in either region with a different base
+                        // address, so keep them separate
+                        id: (Kind::Cartridge, ((bank as u64) << 1) | region as u64),
+                        version: 0,
+                        base_addr: region * 0x4000,
+                        size: 0x4000,
+                    },
+                    &self.rom[base..base + ROM_BANK_SIZE],
+                )
+            }
+            _ => match self.ram_access(addr) {
+                RamAccess::Ram(offset) => self.ram.map_page(offset, addr),
+                // Nobody should be executing from MBC registers, treat them as disabled.  Keep
+                // disabled pages apart from all the real RAM pages.
+                RamAccess::Disabled | RamAccess::Register => (
+                    PageStatus {
+                        id: (Kind::Cram, (1 << 32) | (addr / RAM_PAGE_SIZE) as u64),
+                        version: 0,
+                        base_addr: addr & !(RAM_PAGE_SIZE - 1),
+                        size: RAM_PAGE_SIZE,
+                    },
+                    &DISABLED_RAM,
+                ),
+            },
+        }
+    }
+}
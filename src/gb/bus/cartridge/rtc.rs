@@ -0,0 +1,328 @@
+use std::convert::TryInto;
+use std::rc::Rc;
+use std::time::Instant;
+
+use crate::compiler::CycleState;
+use crate::gb::state::{self, impl_snapshot, Reader, Snapshot};
+
+/// Cycles per second of emulated time
+const CLOCK_RATE: u64 = 4_194_304;
+
+/// Length of the clock footer on .sav files, as written by most other emulators
+pub const FOOTER_LEN: usize = 48;
+
+/// An older form of the footer, with a 32 bit timestamp
+const SHORT_FOOTER_LEN: usize = 44;
+
+const REGISTERS: std::ops::RangeInclusive<u8> = 0x08..=0x0c;
+
+const DAY_HIGH: u8 = 0x01;
+const HALT: u8 = 0x40;
+const DAY_CARRY: u8 = 0x80;
+
+/// Where the clock gets its time from
+pub enum ClockSource {
+    /// Advance with the emulated cycle count, so the clock is deterministic
+    Cycles(Rc<CycleState>),
+    /// Advance with the host's wall clock, like a real cartridge would
+    Host,
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+struct Time {
+    secs: u8,
+    mins: u8,
+    hours: u8,
+    days: u16,
+    carry: bool,
+}
+
+/// The MBC3 real-time clock.  The live time is brought up to date lazily whenever it's accessed,
+/// and the CPU only ever sees the copy made by the last latch.
+pub struct Rtc {
+    source: ClockSource,
+    last_cycle: u64,
+    last_instant: Instant,
+    /// Progress towards the next second, in cycles
+    subsec: u64,
+    halted: bool,
+
+    live: Time,
+    latched: Time,
+    latch_primed: bool,
+}
+
+impl Time {
+    fn advance(&mut self, secs: u64) {
+        let secs = self.secs as u64 + secs;
+        let mins = self.mins as u64 + secs / 60;
+        let hours = self.hours as u64 + mins / 60;
+        let days = self.days as u64 + hours / 24;
+        self.secs = (secs % 60) as u8;
+        self.mins = (mins % 60) as u8;
+        self.hours = (hours % 24) as u8;
+        self.days = (days % 512) as u16;
+        self.carry |= days >= 512;
+    }
+
+    /// Set the registers from their values, leaving the halt flag to the caller
+    fn write(&mut self, reg: u8, val: u8) {
+        match reg {
+            0x08 => self.secs = val & 0x3f,
+            0x09 => self.mins = val & 0x3f,
+            0x0a => self.hours = val & 0x1f,
+            0x0b => self.days = (self.days & 0x100) | val as u16,
+            0x0c => {
+                self.days = (self.days & 0xff) | ((val & DAY_HIGH) as u16) << 8;
+                self.carry = val & DAY_CARRY != 0;
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn read(&self, reg: u8, halted: bool) -> u8 {
+        match reg {
+            0x08 => self.secs,
+            0x09 => self.mins,
+            0x0a => self.hours,
+            0x0b => self.days as u8,
+            0x0c => {
+                let mut val = (self.days >> 8) as u8 & DAY_HIGH;
+                if halted {
+                    val |= HALT;
+                }
+                if self.carry {
+                    val |= DAY_CARRY;
+                }
+                val
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Rtc {
+    pub fn new(source: ClockSource) -> Self {
+        let last_cycle = match &source {
+            ClockSource::Cycles(cycles) => cycles.cycle(),
+            ClockSource::Host => 0,
+        };
+        Rtc {
+            source,
+            last_cycle,
+            last_instant: Instant::now(),
+            subsec: 0,
+            halted: false,
+            live: Default::default(),
+            latched: Default::default(),
+            latch_primed: false,
+        }
+    }
+
+    /// Cycles that have elapsed since the last sync, according to the clock source
+    fn elapsed(&mut self) -> u64 {
+        match &self.source {
+            ClockSource::Cycles(cycles) => {
+                let now = cycles.cycle();
+                let elapsed = now - self.last_cycle;
+                self.last_cycle = now;
+                elapsed
+            }
+            ClockSource::Host => {
+                let now = Instant::now();
+                let elapsed = now - self.last_instant;
+                self.last_instant = now;
+                (elapsed.as_nanos() * CLOCK_RATE as u128 / 1_000_000_000) as u64
+            }
+        }
+    }
+
+    fn sync(&mut self) {
+        let elapsed = self.elapsed();
+        if self.halted {
+            return;
+        }
+        self.subsec += elapsed;
+        self.live.advance(self.subsec / CLOCK_RATE);
+        self.subsec %= CLOCK_RATE;
+    }
+
+    /// Writing 0 then 1 to the latch register copies the live time into the readable registers
+    pub fn write_latch(&mut self, val: u8) {
+        if self.latch_primed && val == 1 {
+            self.sync();
+            self.latched = self.live;
+        }
+        self.latch_primed = val == 0;
+    }
+
+    pub fn read(&self, reg: u8) -> u8 {
+        self.latched.read(reg, self.halted)
+    }
+
+    pub fn write(&mut self, reg: u8, val: u8) {
+        self.sync();
+        self.live.write(reg, val);
+        match reg {
+            0x08 => self.subsec = 0,
+            0x0c => self.halted = val & HALT != 0,
+            _ => (),
+        }
+    }
+
+    /// The footer for a .sav file saved at `now`, in seconds since the UNIX epoch.  It's each of
+    /// the live registers as a 32 bit word, then the latched ones, then `now` as 64 bits.
+    pub fn save_footer(&mut self, now: u64) -> Vec<u8> {
+        self.sync();
+        let mut out = Vec::with_capacity(FOOTER_LEN);
+        for reg in REGISTERS {
+            out.extend_from_slice(&(self.live.read(reg, self.halted) as u32).to_le_bytes());
+        }
+        for reg in REGISTERS {
+            out.extend_from_slice(&(self.latched.read(reg, self.halted) as u32).to_le_bytes());
+        }
+        out.extend_from_slice(&now.to_le_bytes());
+        out
+    }
+
+    /// Restore the clock from a .sav footer, returning false if it's not a length footers come
+    /// in.  A clock following the host catches up on the time since the footer was saved, one
+    /// on emulated time carries on from where it was.
+    pub fn load_footer(&mut self, footer: &[u8], now: u64) -> bool {
+        // Every register fits in the low byte of its word
+        let reg_val = |idx: usize| footer[idx * 4];
+        let saved = match footer.len() {
+            FOOTER_LEN => u64::from_le_bytes(footer[40..48].try_into().unwrap()),
+            SHORT_FOOTER_LEN => u32::from_le_bytes(footer[40..44].try_into().unwrap()) as u64,
+            _ => return false,
+        };
+        for (idx, reg) in REGISTERS.enumerate() {
+            self.live.write(reg, reg_val(idx));
+            self.latched.write(reg, reg_val(idx + 5));
+        }
+        self.halted = reg_val(4) & HALT != 0;
+        self.subsec = 0;
+        self.elapsed();
+
+        if let (ClockSource::Host, false) = (&self.source, self.halted) {
+            self.live.advance(now.saturating_sub(saved));
+        }
+        true
+    }
+}
+
+impl_snapshot!(Time {
+    secs,
+    mins,
+    hours,
+    days,
+    carry,
+});
+
+impl Snapshot for Rtc {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        self.last_cycle.save_state(out);
+        self.subsec.save_state(out);
+        self.halted.save_state(out);
+        self.live.save_state(out);
+        self.latched.save_state(out);
+        self.latch_primed.save_state(out);
+    }
+
+    /// A clock following the host picks up from the time of loading, time spent away from the
+    /// state doesn't count
+    fn load_state(&mut self, r: &mut Reader<'_>) -> Result<(), state::Error> {
+        self.last_cycle.load_state(r)?;
+        self.subsec.load_state(r)?;
+        self.halted.load_state(r)?;
+        self.live.load_state(r)?;
+        self.latched.load_state(r)?;
+        self.latch_primed.load_state(r)?;
+        self.last_instant = Instant::now();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn setup() -> (Rc<CycleState>, Rtc) {
+        let cycles = Rc::new(CycleState::new());
+        let rtc = Rtc::new(ClockSource::Cycles(cycles.clone()));
+        (cycles, rtc)
+    }
+
+    fn latch(rtc: &mut Rtc) {
+        rtc.write_latch(0);
+        rtc.write_latch(1);
+    }
+
+    #[test]
+    fn latched_time_advances_with_cycles() {
+        let (cycles, mut rtc) = setup();
+        cycles.advance(CLOCK_RATE * (3600 + 61) + 5);
+        assert_eq!(rtc.read(0x08), 0);
+        latch(&mut rtc);
+        cycles.advance(CLOCK_RATE * 10);
+        assert_eq!((rtc.read(0x0a), rtc.read(0x09), rtc.read(0x08)), (1, 1, 1));
+    }
+
+    #[test]
+    fn halt_stops_clock() {
+        let (cycles, mut rtc) = setup();
+        rtc.write(0x0c, HALT);
+        cycles.advance(CLOCK_RATE * 100);
+        rtc.write(0x0c, 0);
+        cycles.advance(CLOCK_RATE * 2);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x08), 2);
+        assert_eq!(rtc.read(0x0c), 0);
+    }
+
+    #[test]
+    fn footer_round_trips() {
+        let (cycles, mut rtc) = setup();
+        rtc.write(0x0a, 5);
+        cycles.advance(CLOCK_RATE * 3);
+        latch(&mut rtc);
+        rtc.write(0x0c, DAY_HIGH | HALT);
+        let footer = rtc.save_footer(1000);
+        assert_eq!(footer.len(), FOOTER_LEN);
+
+        let (_, mut loaded) = setup();
+        assert!(loaded.load_footer(&footer, 5000));
+        assert_eq!((loaded.read(0x0a), loaded.read(0x08)), (5, 3));
+        loaded.write(0x0c, DAY_HIGH);
+        latch(&mut loaded);
+        assert_eq!((loaded.read(0x0c), loaded.read(0x08)), (DAY_HIGH, 3));
+        assert!(!loaded.load_footer(&footer[..40], 5000));
+    }
+
+    #[test]
+    fn host_clock_catches_up_from_footer() {
+        let (_, mut rtc) = setup();
+        rtc.write(0x09, 30);
+        let footer = rtc.save_footer(1000);
+
+        let mut loaded = Rtc::new(ClockSource::Host);
+        assert!(loaded.load_footer(&footer, 1000 + 3600 + 45));
+        latch(&mut loaded);
+        assert_eq!((loaded.read(0x0a), loaded.read(0x09)), (1, 30));
+        assert!(loaded.read(0x08) >= 45);
+    }
+
+    #[test]
+    fn day_counter_carries() {
+        let (cycles, mut rtc) = setup();
+        rtc.write(0x0b, 0xff);
+        rtc.write(0x0c, DAY_HIGH);
+        rtc.write(0x0a, 23);
+        rtc.write(0x09, 59);
+        rtc.write(0x08, 59);
+        cycles.advance(CLOCK_RATE);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x0b), 0);
+        assert_eq!(rtc.read(0x0c), DAY_CARRY);
+    }
+}
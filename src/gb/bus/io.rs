@@ -1,4 +1,5 @@
-use crate::gb::devices::Ppu;
+use crate::gb::devices::{Apu, Dma, IntController, Joypad, Ppu, Timer};
+use crate::gb::state::{self, Reader, Snapshot};
 
 use super::{DeviceWrapper, Kind, PageStatus};
 
@@ -36,7 +37,12 @@ impl Io {
         offset: u8,
     ) -> &'d mut dyn Device {
         match offset {
-            0x40..=0x45 | 0x47..=0x49 => devices.ppu,
+            0x00 => devices.joypad,
+            0x04..=0x07 => devices.timer,
+            0x0f | 0xff => devices.int_controller,
+            0x10..=0x3f => devices.apu,
+            0x40..=0x45 | 0x47..=0x4b => devices.ppu,
+            0x46 => devices.dma,
             _ => self,
         }
     }
@@ -67,6 +73,22 @@ impl Io {
     }
 }
 
+impl Snapshot for Io {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        self.mem.save_state(out)
+    }
+
+    fn load_state(&mut self, r: &mut Reader<'_>) -> Result<(), state::Error> {
+        let mut mem = Vec::new();
+        mem.load_state(r)?;
+        if mem.len() != self.mem.len() {
+            return Err(state::Error::Invalid("IO size"));
+        }
+        self.mem = mem;
+        Ok(())
+    }
+}
+
 fn ro_map(offset: u8) -> u8 {
     match offset {
         _ => 0x00,
@@ -106,3 +128,8 @@ macro_rules! impl_device_fwd {
 
 impl_device_fwd!(Io, read_mem, write_mem);
 impl_device_fwd!(Ppu);
+impl_device_fwd!(IntController);
+impl_device_fwd!(Timer);
+impl_device_fwd!(Joypad);
+impl_device_fwd!(Dma);
+impl_device_fwd!(Apu);
@@ -1,6 +1,12 @@
 use std::path::Path;
+use std::rc::Rc;
 
-use crate::gb::devices::Ppu;
+use log::*;
+
+use crate::compiler::CycleState;
+use crate::gb::devices::{Apu, Dma, IntController, Joypad, Ppu, Timer};
+use crate::gb::state::{self, Reader, Snapshot};
+use crate::gb::GbOptions;
 
 pub mod dummy;
 
@@ -17,7 +23,7 @@ mod wram;
 
 pub use bios::Bios;
 pub use bus_wrapper::BusWrapper;
-pub use cartridge::Cartridge;
+pub use cartridge::{Cartridge, CartridgeHeader, RumbleCallback};
 pub use error::Error;
 pub use io::Io;
 pub use kind::Kind;
@@ -26,20 +32,19 @@ pub use ram::Ram;
 use rom::Rom;
 use wram::Wram;
 
+/// Writing to this register unmaps the BIOS from 0x0000-0x00ff
+const BIOS_REG: u16 = 0xff50;
+
 type Oam = Ram;
 type Vram = Ram;
 type Hram = Ram;
 
-// TODO: Fixme with mbc detection
-type CartridgeRam = Ram;
-
 type Unused = Ram;
 
 pub struct Bus {
-    bios: Bios,
+    bios: Option<Bios>,
     cart: Cartridge,
     pub vram: Vram,
-    cram: CartridgeRam,
     wram: Wram,
     pub oam: Oam,
     unused: Unused,
@@ -47,10 +52,17 @@ pub struct Bus {
     hram: Hram,
 
     bios_enabled: bool,
+    /// Set when the BIOS gets unmapped until picked up by `take_bios_unmapped`
+    bios_unmapped: bool,
 }
 
 pub struct DeviceWrapper<'a> {
     ppu: &'a mut Ppu,
+    int_controller: &'a mut IntController,
+    timer: &'a mut Timer,
+    joypad: &'a mut Joypad,
+    dma: &'a mut Dma,
+    apu: &'a mut Apu,
 }
 
 enum MapResult<'a> {
@@ -60,21 +72,79 @@ enum MapResult<'a> {
 
 impl Bus {
     pub fn new<P: AsRef<Path>, R: AsRef<Path>>(
-        bios_path: P,
+        bios_path: Option<P>,
         cartridge_path: R,
+        cycles: Rc<CycleState>,
+        options: &GbOptions,
     ) -> Result<Self, Error> {
-        Ok(Bus {
-            bios: Bios::new(bios_path)?,
-            cart: Cartridge::new(cartridge_path)?,
+        let bios = bios_path.map(Bios::new).transpose()?;
+        let cart = Cartridge::new(cartridge_path, cycles, options)?;
+        Ok(Bus::with_cartridge(bios, cart))
+    }
+
+    /// A bus with a GBS file's ROM image in place of a cartridge, see `Cartridge::new_gbs`
+    pub fn new_gbs(rom: Vec<u8>, title: &str) -> Result<Self, Error> {
+        Ok(Bus::with_cartridge(None, Cartridge::new_gbs(rom, title)?))
+    }
+
+    fn with_cartridge(bios: Option<Bios>, cart: Cartridge) -> Self {
+        Bus {
+            bios_enabled: bios.is_some(),
+            bios,
+            cart,
             vram: Ram::new(Kind::Vram, 0x8000, 0x2000, 0x100),
-            cram: Ram::new(Kind::Cram, 0xA000, 0x2000, 0x100),
             wram: Wram::new(),
             oam: Ram::new(Kind::Oam, 0xFE00, 0xA0, 0xA0),
             unused: Ram::new_with_data(vec![0xff; 0x60], Kind::Unused, 0xFEA0, 0x60),
             io: Io::new(),
             hram: Ram::new(Kind::Hram, 0xFF80, 0x7F, 0x7F),
-            bios_enabled: true,
-        })
+            bios_unmapped: false,
+        }
+    }
+
+    pub fn set_rumble_callback(&mut self, callback: RumbleCallback) {
+        self.cart.set_rumble_callback(callback)
+    }
+
+    pub fn cartridge_header(&self) -> &CartridgeHeader {
+        self.cart.header()
+    }
+
+    pub fn rom_checksum(&self) -> u64 {
+        self.cart.rom_checksum()
+    }
+
+    pub fn save_cartridge_ram(&mut self) -> std::io::Result<()> {
+        self.cart.save()
+    }
+
+    /// Save the state of memory, adding where each page of RAM went to `pages`
+    pub fn save_state_pages(&self, out: &mut Vec<u8>, pages: &mut Vec<state::Page>) {
+        self.bios_enabled.save_state(out);
+        self.cart.save_state_pages(out, pages);
+        self.vram.save_state_pages(out, pages);
+        self.wram.save_state_pages(out, pages);
+        self.oam.save_state_pages(out, pages);
+        self.io.save_state(out);
+        self.hram.save_state_pages(out, pages);
+    }
+
+    /// Returns true once after the BIOS has been unmapped, so that its compiled code can be thrown
+    /// away
+    pub fn take_bios_unmapped(&mut self) -> bool {
+        std::mem::replace(&mut self.bios_unmapped, false)
+    }
+
+    /// Any write to 0xff50 unmaps the BIOS, and there's no way to map it back in
+    fn write_bios_reg(&mut self, val: u8) {
+        if self.bios_enabled {
+            debug!(
+                "BIOS unmapped by write of {:02x?} to {:#06x}",
+                val, BIOS_REG
+            );
+            self.bios_enabled = false;
+            self.bios_unmapped = true;
+        }
     }
 
     fn map_device<'a>(&'a mut self, addr: u16) -> MapResult<'a> {
@@ -87,13 +157,13 @@ impl Bus {
             }
         }
         if self.bios_enabled && addr < 0x100 {
-            return MapResult::Memory(&mut self.bios);
+            return MapResult::Memory(self.bios.as_mut().expect("Enabled BIOS should exist"));
         }
 
         mmap! {
             0x0000..=0x7FFF => cart,
             0x8000..=0x9FFF => vram,
-            0xA000..=0xBFFF => cram,
+            0xA000..=0xBFFF => cart,
             0xC000..=0xFDFF => wram,
             0xFE00..=0xFE9F => oam,
             0xFEA0..=0xFEFF => unused,
@@ -104,13 +174,37 @@ impl Bus {
     }
 
     pub fn read(&mut self, devices: &mut DeviceWrapper<'_>, addr: u16) -> u8 {
+        if addr == BIOS_REG || devices.dma.blocks(addr) || devices.ppu.blocks(addr) {
+            return 0xff;
+        }
         match self.map_device(addr) {
             MapResult::Memory(m) => m.read(addr),
             MapResult::Io(io) => io.read(devices, addr),
         }
     }
 
+    /// Read memory as OAM DMA sees it, which isn't blocked by the PPU or the transfer itself.
+    /// Sources from 0xe000 up don't reach OAM or the registers, they read echo WRAM instead.
+    pub fn dma_read(&mut self, addr: u16) -> u8 {
+        let addr = if addr >= 0xe000 { addr - 0x2000 } else { addr };
+        match self.map_device(addr) {
+            MapResult::Memory(m) => m.read(addr),
+            MapResult::Io(_) => unreachable!("DMA sources never reach IO"),
+        }
+    }
+
     pub fn write(&mut self, devices: &mut DeviceWrapper<'_>, addr: u16, val: u8) {
+        if addr == BIOS_REG {
+            return self.write_bios_reg(val);
+        }
+        if devices.dma.blocks(addr) {
+            trace!("Write to {:#06x} blocked by DMA", addr);
+            return;
+        }
+        if devices.ppu.blocks(addr) {
+            trace!("Write to {:#06x} blocked by the PPU", addr);
+            return;
+        }
         match self.map_device(addr) {
             MapResult::Memory(m) => m.write(addr, val),
             MapResult::Io(io) => io.write(devices, addr, val),
@@ -125,8 +219,43 @@ impl Bus {
     }
 }
 
+/// The BIOS and the unused region never change, so they're left out
+impl Snapshot for Bus {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        self.save_state_pages(out, &mut Vec::new())
+    }
+
+    fn load_state(&mut self, r: &mut Reader<'_>) -> Result<(), state::Error> {
+        self.bios_enabled.load_state(r)?;
+        if self.bios_enabled && self.bios.is_none() {
+            return Err(state::Error::NeedsBios);
+        }
+        self.bios_unmapped = false;
+        self.cart.load_state(r)?;
+        self.vram.load_state(r)?;
+        self.wram.load_state(r)?;
+        self.oam.load_state(r)?;
+        self.io.load_state(r)?;
+        self.hram.load_state(r)
+    }
+}
+
 impl<'a> DeviceWrapper<'a> {
-    pub fn new(ppu: &'a mut Ppu) -> Self {
-        DeviceWrapper { ppu }
+    pub fn new(
+        ppu: &'a mut Ppu,
+        int_controller: &'a mut IntController,
+        timer: &'a mut Timer,
+        joypad: &'a mut Joypad,
+        dma: &'a mut Dma,
+        apu: &'a mut Apu,
+    ) -> Self {
+        DeviceWrapper {
+            ppu,
+            int_controller,
+            timer,
+            joypad,
+            dma,
+            apu,
+        }
     }
 }
@@ -1,3 +1,5 @@
+use crate::gb::state::{self, Reader, Snapshot};
+
 use super::{Kind, Module, PageStatus};
 
 pub struct Ram {
@@ -38,6 +40,27 @@ impl Ram {
             page_size,
         }
     }
+
+    pub fn data(&self) -> &[u8] {
+        &self.mem
+    }
+
+    /// Save the contents, adding where each page went to `pages`
+    pub fn save_state_pages(&self, out: &mut Vec<u8>, pages: &mut Vec<state::Page>) {
+        self.save_state(out);
+        let start = out.len() - self.mem.len();
+        let page_size = self.page_size as usize;
+        pages.extend(
+            self.versions
+                .iter()
+                .enumerate()
+                .map(|(idx, &version)| state::Page {
+                    offset: start + idx * page_size,
+                    len: page_size,
+                    version,
+                }),
+        );
+    }
 }
 
 impl Module for Ram {
@@ -72,3 +95,51 @@ impl Module for Ram {
         )
     }
 }
+
+impl Snapshot for Ram {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        self.mem.save_state(out);
+    }
+
+    /// Versions aren't saved, pages that the load changes move on to a new version like they
+    /// would for a write, so a version never goes back to meaning older contents
+    fn load_state(&mut self, r: &mut Reader<'_>) -> Result<(), state::Error> {
+        let mut mem = Vec::new();
+        mem.load_state(r)?;
+        if mem.len() != self.mem.len() {
+            return Err(state::Error::Invalid("memory size"));
+        }
+        let page_size = self.page_size as usize;
+        let pages = self.mem.chunks(page_size).zip(mem.chunks(page_size));
+        for (version, (old, new)) in self.versions.iter_mut().zip(pages) {
+            if old != new {
+                *version += 1;
+            }
+        }
+        self.mem = mem;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn loading_moves_changed_pages_on() {
+        let mut ram = Ram::new(Kind::Vram, 0x8000, 0x20, 0x10);
+        let mut out = Vec::new();
+        ram.save_state(&mut out);
+        ram.write(0x8001, 1);
+        ram.write(0x8011, 1);
+        ram.write(0x8011, 0);
+
+        let mut pages = Vec::new();
+        ram.load_state(&mut Reader::new(&out)).unwrap();
+        ram.save_state_pages(&mut Vec::new(), &mut pages);
+        // Both pages end up with the contents they started with, at versions never used for them
+        let versions: Vec<u64> = pages.iter().map(|page| page.version).collect();
+        assert_eq!(versions, vec![2, 2]);
+        assert_eq!((pages[1].offset, pages[1].len), (4 + 0x10, 0x10));
+    }
+}
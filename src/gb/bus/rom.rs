@@ -13,6 +13,10 @@ impl Rom {
             data: fs::read(path)?,
         })
     }
+
+    pub fn from_data(data: Vec<u8>) -> Self {
+        Rom { data }
+    }
 }
 
 impl Deref for Rom {
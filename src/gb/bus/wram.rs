@@ -1,5 +1,7 @@
 use derive_more::From;
 
+use crate::gb::state::{self, Reader, Snapshot};
+
 use super::{Kind, Module, PageStatus, Ram};
 
 #[derive(From)]
@@ -9,6 +11,10 @@ impl Wram {
     pub fn new() -> Self {
         Ram::new(Kind::Wram, 0xC000, 0x2000, 0x10).into()
     }
+
+    pub fn save_state_pages(&self, out: &mut Vec<u8>, pages: &mut Vec<state::Page>) {
+        self.0.save_state_pages(out, pages)
+    }
 }
 
 impl Module for Wram {
@@ -29,3 +35,13 @@ impl Module for Wram {
         (ps, data)
     }
 }
+
+impl Snapshot for Wram {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        self.0.save_state(out)
+    }
+
+    fn load_state(&mut self, r: &mut Reader<'_>) -> Result<(), state::Error> {
+        self.0.load_state(r)
+    }
+}
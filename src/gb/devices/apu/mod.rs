@@ -0,0 +1,376 @@
+//! The audio processing unit.  Like the timer nothing here ticks on its own: the channels are
+//! brought up to date from the cycle count whenever their registers are accessed, and at every
+//! frame sequencer step through the APU's event so the sample buffer never falls far behind.
+
+use std::rc::Rc;
+
+use crate::compiler::CycleState;
+use crate::gb::state::{self, Reader, Snapshot};
+
+use super::EventCycle;
+
+mod noise;
+mod square;
+mod units;
+mod wave;
+
+use noise::Noise;
+use square::Square;
+use wave::Wave;
+
+/// Cycles between samples, the APU puts one out every machine cycle
+pub const CYCLES_PER_SAMPLE: u64 = 4;
+
+/// Samples per second, from the 4194304Hz clock
+pub const SAMPLE_RATE: u32 = 4_194_304 / CYCLES_PER_SAMPLE as u32;
+
+/// The frame sequencer clocks length counters, sweep and envelopes at 512Hz
+const SEQUENCER_PERIOD: u64 = 8192;
+
+/// How much of the output the high-pass filter keeps each sample, modelling the capacitors on the
+/// DMG's outputs that remove the DC offset the DACs leave
+const CHARGE_FACTOR: f32 = 0.999_832;
+
+/// Mixed output of one side at full volume with every channel at its peak
+const MAX_MIX: f32 = 15.0 * 4.0 * 8.0;
+
+/// One sample of output, taken every `CYCLES_PER_SAMPLE` cycles
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Sample {
+    /// The digital output of each channel before its DAC, from 0 to 15
+    pub channels: [u8; 4],
+    pub left: i16,
+    pub right: i16,
+}
+
+trait Channel {
+    /// Read a register, offset from the first of the channel's 5
+    fn read(&self, reg: u8) -> u8;
+    fn write(&mut self, reg: u8, val: u8);
+    /// Load the length counter from an NRx1 write, which still happens with the APU off
+    fn write_length(&mut self, val: u8);
+    fn enabled(&self) -> bool;
+    /// The channel's digital output, or None when its DAC is off
+    fn output(&self) -> Option<u8>;
+    /// Run the channel's frequency timer for some cycles
+    fn tick(&mut self, cycles: u32);
+    fn clock_length(&mut self);
+    fn clock_envelope(&mut self) {}
+    /// Reset everything the APU being turned off clears
+    fn power_off(&mut self);
+}
+
+pub struct Apu {
+    cycles: Rc<CycleState>,
+    /// Cycle up to which the channels and the sample buffer are up to date
+    synced: u64,
+    /// Cycle of the next frame sequencer step
+    next_step: u64,
+    step: u8,
+
+    powered: bool,
+    ch1: Square,
+    ch2: Square,
+    ch3: Wave,
+    ch4: Noise,
+    /// NR50, the volume of each side
+    volume: u8,
+    /// NR51, which channels go to which side
+    panning: u8,
+
+    /// High-pass filter state for the left and right sides
+    capacitors: (f32, f32),
+    samples: Vec<Sample>,
+}
+
+impl Apu {
+    pub fn new(cycles: Rc<CycleState>) -> Self {
+        let current_cycle = cycles.cycle();
+        Apu {
+            cycles,
+            synced: current_cycle,
+            next_step: current_cycle + SEQUENCER_PERIOD,
+            step: 0,
+            powered: false,
+            ch1: Square::new(true),
+            ch2: Square::new(false),
+            ch3: Wave::new(),
+            ch4: Noise::new(),
+            volume: 0,
+            panning: 0,
+            capacitors: (0.0, 0.0),
+            samples: Vec::new(),
+        }
+    }
+
+    fn channels(&self) -> [&dyn Channel; 4] {
+        [&self.ch1, &self.ch2, &self.ch3, &self.ch4]
+    }
+
+    fn channels_mut(&mut self) -> [&mut dyn Channel; 4] {
+        [&mut self.ch1, &mut self.ch2, &mut self.ch3, &mut self.ch4]
+    }
+
+    /// Run the APU up to the current cycle, filling the sample buffer along the way
+    fn sync(&mut self) {
+        let now = self.cycles.cycle();
+        while self.synced + CYCLES_PER_SAMPLE <= now {
+            self.synced += CYCLES_PER_SAMPLE;
+            if self.synced >= self.next_step {
+                self.next_step += SEQUENCER_PERIOD;
+                if self.powered {
+                    self.step_sequencer();
+                }
+            }
+            if self.powered {
+                for channel in self.channels_mut().iter_mut() {
+                    channel.tick(CYCLES_PER_SAMPLE as u32);
+                }
+            }
+            let sample = self.mix();
+            self.samples.push(sample);
+        }
+    }
+
+    fn step_sequencer(&mut self) {
+        if self.step % 2 == 0 {
+            for channel in self.channels_mut().iter_mut() {
+                channel.clock_length();
+            }
+        }
+        if self.step == 2 || self.step == 6 {
+            self.ch1.clock_sweep();
+        }
+        if self.step == 7 {
+            for channel in self.channels_mut().iter_mut() {
+                channel.clock_envelope();
+            }
+        }
+        self.step = (self.step + 1) % 8;
+    }
+
+    fn mix(&mut self) -> Sample {
+        let mut sample = Sample::default();
+        let (mut left, mut right) = (0i32, 0i32);
+        if self.powered {
+            for (i, channel) in self.channels().iter().enumerate() {
+                let output = match channel.output() {
+                    Some(output) => output,
+                    None => continue,
+                };
+                sample.channels[i] = output;
+                // The DACs map 0 to 15 onto -1 to 1, scaled up by 15 here
+                let analog = output as i32 * 2 - 15;
+                if self.panning & (0x10 << i) != 0 {
+                    left += analog;
+                }
+                if self.panning & (0x1 << i) != 0 {
+                    right += analog;
+                }
+            }
+            left *= ((self.volume >> 4) & 0x7) as i32 + 1;
+            right *= (self.volume & 0x7) as i32 + 1;
+        }
+
+        sample.left = high_pass(&mut self.capacitors.0, left);
+        sample.right = high_pass(&mut self.capacitors.1, right);
+        sample
+    }
+
+    /// Returns the next cycle the APU wants to be synced at
+    pub fn process(&mut self) -> EventCycle {
+        self.sync();
+        self.next_step
+    }
+
+    /// Hand out the samples put out since the last call
+    pub fn take_samples(&mut self) -> Vec<Sample> {
+        self.sync();
+        std::mem::take(&mut self.samples)
+    }
+
+    fn power_off(&mut self) {
+        for channel in self.channels_mut().iter_mut() {
+            channel.power_off();
+        }
+        self.volume = 0;
+        self.panning = 0;
+        self.powered = false;
+    }
+
+    fn power_on(&mut self) {
+        self.powered = true;
+        self.step = 0;
+    }
+
+    pub fn read(&mut self, offset: u8) -> u8 {
+        self.sync();
+        match offset {
+            0x10..=0x14 => self.ch1.read(offset - 0x10),
+            0x15..=0x19 => self.ch2.read(offset - 0x15),
+            0x1a..=0x1e => self.ch3.read(offset - 0x1a),
+            0x1f..=0x23 => self.ch4.read(offset - 0x1f),
+            0x24 => self.volume,
+            0x25 => self.panning,
+            0x26 => {
+                let status = self
+                    .channels()
+                    .iter()
+                    .enumerate()
+                    .fold(0, |acc, (i, channel)| {
+                        acc | ((channel.enabled() as u8) << i)
+                    });
+                ((self.powered as u8) << 7) | 0x70 | status
+            }
+            0x27..=0x2f => 0xff,
+            0x30..=0x3f => self.ch3.read_ram(offset as usize - 0x30),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write(&mut self, offset: u8, val: u8) {
+        self.sync();
+        // Only NR52, wave RAM and the length counters can be written with the APU off
+        if !self.powered && offset < 0x26 {
+            match offset {
+                0x11 => self.ch1.write_length(val),
+                0x16 => self.ch2.write_length(val),
+                0x1b => self.ch3.write_length(val),
+                0x20 => self.ch4.write_length(val),
+                _ => (),
+            }
+            return;
+        }
+        match offset {
+            0x10..=0x14 => self.ch1.write(offset - 0x10, val),
+            0x15..=0x19 => self.ch2.write(offset - 0x15, val),
+            0x1a..=0x1e => self.ch3.write(offset - 0x1a, val),
+            0x1f..=0x23 => self.ch4.write(offset - 0x1f, val),
+            0x24 => self.volume = val,
+            0x25 => self.panning = val,
+            0x26 => match (self.powered, val & 0x80 != 0) {
+                (true, false) => self.power_off(),
+                (false, true) => self.power_on(),
+                _ => (),
+            },
+            0x27..=0x2f => (),
+            0x30..=0x3f => self.ch3.write_ram(offset as usize - 0x30, val),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Filter one side's mixed output, converting it to a 16 bit sample
+fn high_pass(capacitor: &mut f32, mixed: i32) -> i16 {
+    let input = mixed as f32 / MAX_MIX;
+    let output = input - *capacitor;
+    *capacitor = input - output * CHARGE_FACTOR;
+    (output.max(-1.0).min(1.0) * i16::MAX as f32) as i16
+}
+
+/// Samples are taken between frames, so the buffer is always empty when states are saved
+impl Snapshot for Apu {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        self.synced.save_state(out);
+        self.next_step.save_state(out);
+        self.step.save_state(out);
+        self.powered.save_state(out);
+        self.ch1.save_state(out);
+        self.ch2.save_state(out);
+        self.ch3.save_state(out);
+        self.ch4.save_state(out);
+        self.volume.save_state(out);
+        self.panning.save_state(out);
+        self.capacitors.save_state(out);
+    }
+
+    fn load_state(&mut self, r: &mut Reader<'_>) -> Result<(), state::Error> {
+        self.synced.load_state(r)?;
+        self.next_step.load_state(r)?;
+        self.step.load_state(r)?;
+        self.powered.load_state(r)?;
+        self.ch1.load_state(r)?;
+        self.ch2.load_state(r)?;
+        self.ch3.load_state(r)?;
+        self.ch4.load_state(r)?;
+        self.volume.load_state(r)?;
+        self.panning.load_state(r)?;
+        self.capacitors.load_state(r)?;
+        self.samples.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn setup() -> (Rc<CycleState>, Apu) {
+        let cycles = Rc::new(CycleState::new());
+        let mut apu = Apu::new(cycles.clone());
+        apu.write(0x26, 0x80);
+        (cycles, apu)
+    }
+
+    #[test]
+    fn power_off_clears_registers() {
+        let (_, mut apu) = setup();
+        apu.write(0x11, 0x80);
+        apu.write(0x12, 0xf0);
+        apu.write(0x25, 0xff);
+        apu.write(0x30, 0x12);
+        assert_eq!(apu.read(0x11), 0xbf);
+        assert_eq!(apu.read(0x12), 0xf0);
+
+        apu.write(0x26, 0x00);
+        assert_eq!(apu.read(0x26), 0x70);
+        assert_eq!(apu.read(0x11), 0x3f);
+        assert_eq!(apu.read(0x12), 0x00);
+        assert_eq!(apu.read(0x25), 0x00);
+        assert_eq!(apu.read(0x30), 0x12);
+
+        apu.write(0x12, 0xf0);
+        assert_eq!(apu.read(0x12), 0x00);
+    }
+
+    #[test]
+    fn length_turns_channel_off() {
+        let (cycles, mut apu) = setup();
+        apu.write(0x25, 0x22);
+        apu.write(0x17, 0xf0);
+        // 2 steps of length left, with length enabled and triggered
+        apu.write(0x16, 0x3e);
+        apu.write(0x19, 0xc7);
+        assert_eq!(apu.read(0x26), 0xf2);
+
+        cycles.advance(SEQUENCER_PERIOD);
+        assert_eq!(apu.read(0x26), 0xf2);
+        cycles.advance(SEQUENCER_PERIOD * 2);
+        assert_eq!(apu.read(0x26), 0xf0);
+
+        let samples = apu.take_samples();
+        assert_eq!(
+            samples.len() as u64,
+            SEQUENCER_PERIOD * 3 / CYCLES_PER_SAMPLE
+        );
+        assert!(samples.iter().any(|sample| sample.channels[1] == 15));
+        assert_eq!(samples.last().unwrap().channels[1], 0);
+    }
+
+    #[test]
+    fn length_written_while_off() {
+        let cycles = Rc::new(CycleState::new());
+        let mut apu = Apu::new(cycles.clone());
+        // 2 steps of length left, loaded before the APU is turned on
+        apu.write(0x20, 0x3e);
+        apu.write(0x26, 0x80);
+        apu.write(0x21, 0xf0);
+        apu.write(0x23, 0xc0);
+        assert_eq!(apu.read(0x26), 0xf8);
+
+        cycles.advance(SEQUENCER_PERIOD);
+        assert_eq!(apu.read(0x26), 0xf8);
+        cycles.advance(SEQUENCER_PERIOD * 2);
+        assert_eq!(apu.read(0x26), 0xf0);
+    }
+}
@@ -0,0 +1,139 @@
+use crate::gb::state::impl_snapshot;
+
+use super::units::{Envelope, Length};
+use super::Channel;
+
+const DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+/// Channel 4, which plays the output of a linear feedback shift register
+#[derive(Debug, Clone)]
+pub(super) struct Noise {
+    enabled: bool,
+    length: Length,
+    envelope: Envelope,
+    /// NR43: the clock shift, the register width and the divisor
+    poly: u8,
+    timer: u32,
+    lfsr: u16,
+}
+
+impl_snapshot!(Noise {
+    enabled,
+    length,
+    envelope,
+    poly,
+    timer,
+    lfsr,
+});
+
+impl Noise {
+    pub(super) fn new() -> Self {
+        Noise {
+            enabled: false,
+            length: Length::new(64),
+            envelope: Envelope::default(),
+            poly: 0,
+            timer: DIVISORS[0],
+            lfsr: 0x7fff,
+        }
+    }
+
+    fn period(&self) -> u32 {
+        DIVISORS[(self.poly & 0x7) as usize] << (self.poly >> 4)
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.timer = self.period();
+        self.envelope.trigger();
+        self.lfsr = 0x7fff;
+    }
+
+    fn shift(&mut self) {
+        let bit = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+        self.lfsr = (self.lfsr >> 1) | (bit << 14);
+        // In 7-bit mode the feedback goes into bit 6 as well, making a much shorter sequence
+        if self.poly & 0x8 != 0 {
+            self.lfsr = (self.lfsr & !0x40) | (bit << 6);
+        }
+    }
+}
+
+impl Channel for Noise {
+    fn read(&self, reg: u8) -> u8 {
+        match reg {
+            0 | 1 => 0xff,
+            2 => self.envelope.read(),
+            3 => self.poly,
+            4 => 0xbf | ((self.length.enabled as u8) << 6),
+            _ => unreachable!(),
+        }
+    }
+
+    fn write(&mut self, reg: u8, val: u8) {
+        match reg {
+            0 => (),
+            1 => self.write_length(val),
+            2 => {
+                self.envelope.write(val);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.poly = val,
+            4 => {
+                self.length.enabled = val & 0x40 != 0;
+                if val & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn write_length(&mut self, val: u8) {
+        self.length.load(val & 0x3f);
+    }
+
+    fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn output(&self) -> Option<u8> {
+        if !self.envelope.dac_enabled() {
+            return None;
+        }
+        Some(if self.enabled && self.lfsr & 1 == 0 {
+            self.envelope.volume
+        } else {
+            0
+        })
+    }
+
+    fn tick(&mut self, mut cycles: u32) {
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.shift();
+        }
+        self.timer -= cycles;
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    fn power_off(&mut self) {
+        let length = self.length.clone();
+        *self = Noise::new();
+        self.length = length;
+        self.length.enabled = false;
+    }
+}
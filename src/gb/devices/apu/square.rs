@@ -0,0 +1,160 @@
+use crate::gb::state::impl_snapshot;
+
+use super::units::{Envelope, Length, Sweep};
+use super::Channel;
+
+/// Waveforms for each duty setting, played from the high bit down
+const DUTY_WAVES: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+
+/// Channels 1 and 2, square waves with a volume envelope.  Channel 1 also has a frequency sweep.
+#[derive(Debug, Clone)]
+pub(super) struct Square {
+    enabled: bool,
+    sweep: Option<Sweep>,
+    duty: u8,
+    length: Length,
+    envelope: Envelope,
+    freq: u16,
+    /// Cycles left until the next step through the waveform
+    timer: u32,
+    step: u8,
+}
+
+impl_snapshot!(Square {
+    enabled,
+    sweep,
+    duty,
+    length,
+    envelope,
+    freq,
+    timer,
+    step,
+});
+
+impl Square {
+    pub(super) fn new(sweep: bool) -> Self {
+        Square {
+            enabled: false,
+            sweep: if sweep { Some(Sweep::default()) } else { None },
+            duty: 0,
+            length: Length::new(64),
+            envelope: Envelope::default(),
+            freq: 0,
+            timer: 2048 * 4,
+            step: 0,
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.freq as u32) * 4
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.timer = self.period();
+        self.envelope.trigger();
+        if let Some(sweep) = &mut self.sweep {
+            if !sweep.trigger(self.freq) {
+                self.enabled = false;
+            }
+        }
+    }
+
+    pub(super) fn clock_sweep(&mut self) {
+        if let Some(sweep) = &mut self.sweep {
+            if !sweep.clock(&mut self.freq) {
+                self.enabled = false;
+            }
+        }
+    }
+}
+
+impl Channel for Square {
+    fn read(&self, reg: u8) -> u8 {
+        match reg {
+            0 => self.sweep.as_ref().map_or(0xff, Sweep::read),
+            1 => (self.duty << 6) | 0x3f,
+            2 => self.envelope.read(),
+            3 => 0xff,
+            4 => 0xbf | ((self.length.enabled as u8) << 6),
+            _ => unreachable!(),
+        }
+    }
+
+    fn write(&mut self, reg: u8, val: u8) {
+        match reg {
+            0 => {
+                if let Some(sweep) = &mut self.sweep {
+                    sweep.write(val);
+                }
+            }
+            1 => {
+                self.duty = val >> 6;
+                self.write_length(val);
+            }
+            2 => {
+                self.envelope.write(val);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.freq = (self.freq & 0x700) | val as u16,
+            4 => {
+                self.freq = (self.freq & 0xff) | ((val as u16 & 0x7) << 8);
+                self.length.enabled = val & 0x40 != 0;
+                if val & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn write_length(&mut self, val: u8) {
+        self.length.load(val & 0x3f);
+    }
+
+    fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn output(&self) -> Option<u8> {
+        if !self.envelope.dac_enabled() {
+            return None;
+        }
+        let high = DUTY_WAVES[self.duty as usize] & (0x80 >> self.step) != 0;
+        Some(if self.enabled && high {
+            self.envelope.volume
+        } else {
+            0
+        })
+    }
+
+    fn tick(&mut self, mut cycles: u32) {
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.step = (self.step + 1) % 8;
+        }
+        self.timer -= cycles;
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    fn power_off(&mut self) {
+        // Length counters survive powering off on the DMG
+        let length = self.length.clone();
+        *self = Square::new(self.sweep.is_some());
+        self.length = length;
+        self.length.enabled = false;
+    }
+}
@@ -0,0 +1,183 @@
+//! The parts of channels that are clocked by the frame sequencer rather than the channel timer
+
+use crate::gb::state::impl_snapshot;
+
+/// Highest frequency that fits in the 11 bits of NRx3/NRx4
+pub(super) const MAX_FREQ: u16 = 0x7ff;
+
+/// Envelope and sweep periods of 0 count down as if they were 8
+fn period_or_8(period: u8) -> u8 {
+    if period == 0 {
+        8
+    } else {
+        period
+    }
+}
+
+/// Turns the channel off once it counts down to 0, if enabled
+#[derive(Debug, Clone)]
+pub(super) struct Length {
+    max: u16,
+    counter: u16,
+    pub(super) enabled: bool,
+}
+
+impl_snapshot!(Length { counter, enabled });
+
+impl Length {
+    pub(super) fn new(max: u16) -> Self {
+        Length {
+            max,
+            counter: 0,
+            enabled: false,
+        }
+    }
+
+    pub(super) fn load(&mut self, val: u8) {
+        self.counter = self.max - val as u16;
+    }
+
+    pub(super) fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    /// Returns whether the channel should be turned off
+    pub(super) fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            self.counter == 0
+        } else {
+            false
+        }
+    }
+}
+
+/// The volume envelope of NRx2
+#[derive(Debug, Default, Clone)]
+pub(super) struct Envelope {
+    reg: u8,
+    pub(super) volume: u8,
+    timer: u8,
+}
+
+impl_snapshot!(Envelope { reg, volume, timer });
+
+impl Envelope {
+    pub(super) fn read(&self) -> u8 {
+        self.reg
+    }
+
+    pub(super) fn write(&mut self, val: u8) {
+        self.reg = val;
+    }
+
+    /// The channel's DAC is off when the envelope can only ever be silent
+    pub(super) fn dac_enabled(&self) -> bool {
+        self.reg & 0xf8 != 0
+    }
+
+    fn period(&self) -> u8 {
+        self.reg & 0x7
+    }
+
+    pub(super) fn trigger(&mut self) {
+        self.volume = self.reg >> 4;
+        self.timer = period_or_8(self.period());
+    }
+
+    pub(super) fn clock(&mut self) {
+        if self.period() == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = self.period();
+        if self.reg & 0x8 != 0 {
+            if self.volume < 15 {
+                self.volume += 1;
+            }
+        } else if self.volume > 0 {
+            self.volume -= 1;
+        }
+    }
+}
+
+/// The frequency sweep of NR10, which only channel 1 has
+#[derive(Debug, Default, Clone)]
+pub(super) struct Sweep {
+    reg: u8,
+    enabled: bool,
+    /// Copy of the frequency the sweep works from, writes to NR13/NR14 don't affect it
+    shadow: u16,
+    timer: u8,
+}
+
+impl_snapshot!(Sweep {
+    reg,
+    enabled,
+    shadow,
+    timer,
+});
+
+impl Sweep {
+    pub(super) fn read(&self) -> u8 {
+        self.reg | 0x80
+    }
+
+    pub(super) fn write(&mut self, val: u8) {
+        self.reg = val & 0x7f;
+    }
+
+    fn period(&self) -> u8 {
+        (self.reg >> 4) & 0x7
+    }
+
+    fn shift(&self) -> u8 {
+        self.reg & 0x7
+    }
+
+    fn calculate(&self) -> u16 {
+        let delta = self.shadow >> self.shift();
+        if self.reg & 0x8 != 0 {
+            self.shadow - delta
+        } else {
+            self.shadow + delta
+        }
+    }
+
+    /// Returns whether the channel stays on, an overflowing first calculation turns it off
+    pub(super) fn trigger(&mut self, freq: u16) -> bool {
+        self.shadow = freq;
+        self.timer = period_or_8(self.period());
+        self.enabled = self.period() != 0 || self.shift() != 0;
+        self.shift() == 0 || self.calculate() <= MAX_FREQ
+    }
+
+    /// Move the frequency along when the sweep timer runs out.  Returns whether the channel stays
+    /// on, it's turned off as soon as the frequency would overflow.
+    pub(super) fn clock(&mut self, freq: &mut u16) -> bool {
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return true;
+        }
+        self.timer = period_or_8(self.period());
+        if !self.enabled || self.period() == 0 {
+            return true;
+        }
+
+        let new = self.calculate();
+        if new > MAX_FREQ {
+            return false;
+        }
+        if self.shift() != 0 {
+            self.shadow = new;
+            *freq = new;
+        }
+        // The next frequency is checked straight away as well
+        self.calculate() <= MAX_FREQ
+    }
+}
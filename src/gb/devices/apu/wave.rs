@@ -0,0 +1,163 @@
+use crate::gb::state::impl_snapshot;
+
+use super::units::Length;
+use super::Channel;
+
+/// Right shift of the 4-bit samples for each NR32 output level, muting at 0
+const VOLUME_SHIFTS: [u8; 4] = [4, 0, 1, 2];
+
+pub(super) const WAVE_RAM_LEN: usize = 16;
+
+/// Channel 3, which plays back 32 4-bit samples from wave RAM
+#[derive(Debug, Clone)]
+pub(super) struct Wave {
+    enabled: bool,
+    dac_enabled: bool,
+    length: Length,
+    volume: u8,
+    freq: u16,
+    timer: u32,
+    position: u8,
+    ram: [u8; WAVE_RAM_LEN],
+}
+
+impl_snapshot!(Wave {
+    enabled,
+    dac_enabled,
+    length,
+    volume,
+    freq,
+    timer,
+    position,
+    ram,
+});
+
+impl Wave {
+    pub(super) fn new() -> Self {
+        Wave {
+            enabled: false,
+            dac_enabled: false,
+            length: Length::new(256),
+            volume: 0,
+            freq: 0,
+            timer: 2048 * 2,
+            position: 0,
+            ram: [0; WAVE_RAM_LEN],
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.freq as u32) * 2
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.timer = self.period();
+        self.position = 0;
+    }
+
+    fn sample(&self) -> u8 {
+        let byte = self.ram[self.position as usize / 2];
+        if self.position % 2 == 0 {
+            byte >> 4
+        } else {
+            byte & 0xf
+        }
+    }
+
+    /// While the channel plays, the cpu sees the byte being played no matter which one it reads
+    pub(super) fn read_ram(&self, idx: usize) -> u8 {
+        if self.enabled {
+            self.ram[self.position as usize / 2]
+        } else {
+            self.ram[idx]
+        }
+    }
+
+    pub(super) fn write_ram(&mut self, idx: usize, val: u8) {
+        if self.enabled {
+            self.ram[self.position as usize / 2] = val;
+        } else {
+            self.ram[idx] = val;
+        }
+    }
+}
+
+impl Channel for Wave {
+    fn read(&self, reg: u8) -> u8 {
+        match reg {
+            0 => ((self.dac_enabled as u8) << 7) | 0x7f,
+            1 => 0xff,
+            2 => (self.volume << 5) | 0x9f,
+            3 => 0xff,
+            4 => 0xbf | ((self.length.enabled as u8) << 6),
+            _ => unreachable!(),
+        }
+    }
+
+    fn write(&mut self, reg: u8, val: u8) {
+        match reg {
+            0 => {
+                self.dac_enabled = val & 0x80 != 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.write_length(val),
+            2 => self.volume = (val >> 5) & 0x3,
+            3 => self.freq = (self.freq & 0x700) | val as u16,
+            4 => {
+                self.freq = (self.freq & 0xff) | ((val as u16 & 0x7) << 8);
+                self.length.enabled = val & 0x40 != 0;
+                if val & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn write_length(&mut self, val: u8) {
+        self.length.load(val);
+    }
+
+    fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn output(&self) -> Option<u8> {
+        if !self.dac_enabled {
+            return None;
+        }
+        Some(if self.enabled {
+            self.sample() >> VOLUME_SHIFTS[self.volume as usize]
+        } else {
+            0
+        })
+    }
+
+    fn tick(&mut self, mut cycles: u32) {
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) % 32;
+        }
+        self.timer -= cycles;
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn power_off(&mut self) {
+        // Wave RAM and the length counter are left alone
+        let (length, ram) = (self.length.clone(), self.ram);
+        *self = Wave::new();
+        self.length = length;
+        self.length.enabled = false;
+        self.ram = ram;
+    }
+}
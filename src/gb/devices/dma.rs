@@ -0,0 +1,117 @@
+use std::rc::Rc;
+
+use crate::compiler::CycleState;
+use crate::gb::state::impl_snapshot;
+
+use super::EventCycle;
+
+/// Cycles between the write to 0xff46 and the transfer taking over the bus
+const START_DELAY: u64 = 4;
+
+/// Number of bytes copied into OAM
+pub const DMA_LEN: u16 = 0xa0;
+
+/// One byte is copied every machine cycle
+const TRANSFER_CYCLES: u64 = DMA_LEN as u64 * 4;
+
+/// OAM DMA, started by writing the high byte of the source address to 0xff46.  While a transfer
+/// runs the cpu can only reach HRAM and the registers.  Nothing outside of HRAM can change the
+/// source while it's blocked, so the copy itself is done all at once when the transfer finishes.
+pub struct Dma {
+    cycles: Rc<CycleState>,
+    source: u8,
+    /// Cycles during which the bus is blocked, from the start of the transfer to its end
+    active: Option<(u64, u64)>,
+    reschedule: bool,
+}
+
+impl_snapshot!(Dma {
+    source,
+    active,
+    reschedule,
+});
+
+impl Dma {
+    pub fn new(cycles: Rc<CycleState>) -> Self {
+        Dma {
+            cycles,
+            source: 0xff,
+            active: None,
+            reschedule: false,
+        }
+    }
+
+    /// Whether the transfer currently in progress keeps the cpu from accessing an address
+    pub fn blocks(&self, addr: u16) -> bool {
+        match self.active {
+            Some((start, end)) => {
+                let now = self.cycles.cycle();
+                addr < 0xff00 && now >= start && now < end
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the cycle the new transfer ends at if one was started since the last call
+    pub fn take_reschedule(&mut self) -> Option<EventCycle> {
+        if self.reschedule {
+            self.reschedule = false;
+            self.active.map(|(_, end)| end)
+        } else {
+            None
+        }
+    }
+
+    /// Finish the transfer, returning the address to copy OAM from
+    pub fn process(&mut self) -> u16 {
+        self.active = None;
+        (self.source as u16) << 8
+    }
+
+    pub fn read(&mut self, offset: u8) -> u8 {
+        match offset {
+            0x46 => self.source,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write(&mut self, offset: u8, val: u8) {
+        match offset {
+            0x46 => {
+                let start = self.cycles.cycle() + START_DELAY;
+                self.source = val;
+                self.active = Some((start, start + TRANSFER_CYCLES));
+                self.reschedule = true;
+                // The code that started the transfer has to stop accessing the bus, so make sure
+                // it doesn't get to run past the point where the end should be scheduled
+                self.cycles.force_stop();
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn blocks_everything_but_hram() {
+        let cycles = Rc::new(CycleState::new());
+        let mut dma = Dma::new(cycles.clone());
+        dma.write(0x46, 0xc1);
+        let end = dma.take_reschedule().unwrap();
+        assert_eq!(end, START_DELAY + TRANSFER_CYCLES);
+        assert!(!dma.blocks(0xc000));
+
+        cycles.advance(START_DELAY);
+        assert!(dma.blocks(0xc000));
+        assert!(dma.blocks(0xfe00));
+        assert!(!dma.blocks(0xff80));
+        assert!(!dma.blocks(0xff46));
+
+        cycles.advance(TRANSFER_CYCLES);
+        assert_eq!(dma.process(), 0xc100);
+        assert!(!dma.blocks(0xc000));
+    }
+}
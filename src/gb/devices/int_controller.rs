@@ -1,7 +1,111 @@
+use std::rc::Rc;
+
 use crate::compiler::CycleState;
+use crate::gb::state::{self, Reader, Snapshot};
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Interrupt {
+    Vblank,
+    Stat,
+    Timer,
+    Serial,
+    Joypad,
+}
+
+const ALL_INTERRUPTS: [Interrupt; 5] = [
+    Interrupt::Vblank,
+    Interrupt::Stat,
+    Interrupt::Timer,
+    Interrupt::Serial,
+    Interrupt::Joypad,
+];
+
+impl Interrupt {
+    fn bit(self) -> u8 {
+        1u8 << (self as u8)
+    }
 
+    /// The address the cpu jumps to when servicing this interrupt
+    pub fn vector(self) -> u16 {
+        0x40 + 8 * (self as u16)
+    }
+}
+
+/// Holds the IF (0xff0f) and IE (0xffff) registers.  Whenever the set of interrupts that are both
+/// requested and enabled changes, the interrupt limit in the cycle state is updated so that the
+/// compiled code exits as soon as it is able to service them.
 pub struct IntController {
-    cyles: Rc<CycleState>,
+    cycles: Rc<CycleState>,
     enable: u8,
     request: u8,
 }
+
+impl IntController {
+    pub fn new(cycles: Rc<CycleState>) -> Self {
+        let controller = IntController {
+            cycles,
+            enable: 0,
+            request: 0,
+        };
+        controller.update_limit();
+        controller
+    }
+
+    pub fn raise(&mut self, int: Interrupt) {
+        self.request |= int.bit();
+        self.update_limit();
+    }
+
+    /// Whether any interrupt is both requested and enabled, regardless of the IME state
+    pub fn pending(&self) -> bool {
+        self.enable & self.request & 0x1f != 0
+    }
+
+    /// Clear the highest priority pending interrupt and return it
+    pub fn acknowledge(&mut self) -> Option<Interrupt> {
+        let active = self.enable & self.request;
+        let int = ALL_INTERRUPTS
+            .iter()
+            .copied()
+            .find(|int| active & int.bit() != 0)?;
+        self.request &= !int.bit();
+        self.update_limit();
+        Some(int)
+    }
+
+    fn update_limit(&self) {
+        let limit = if self.pending() { 0 } else { std::u64::MAX };
+        self.cycles.set_interrupt_limit(limit);
+    }
+
+    pub fn read(&mut self, offset: u8) -> u8 {
+        match offset {
+            0x0f => self.request | 0xe0,
+            0xff => self.enable,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write(&mut self, offset: u8, val: u8) {
+        match offset {
+            0x0f => self.request = val & 0x1f,
+            0xff => self.enable = val,
+            _ => unreachable!(),
+        }
+        self.update_limit();
+    }
+}
+
+impl Snapshot for IntController {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        self.enable.save_state(out);
+        self.request.save_state(out);
+    }
+
+    fn load_state(&mut self, r: &mut Reader<'_>) -> Result<(), state::Error> {
+        self.enable.load_state(r)?;
+        self.request.load_state(r)?;
+        self.update_limit();
+        Ok(())
+    }
+}
@@ -0,0 +1,99 @@
+use crate::gb::state::impl_snapshot;
+
+use super::{IntController, Interrupt};
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    pub fn bit(self) -> u8 {
+        1u8 << (self as u8)
+    }
+}
+
+/// The P1 register (0xff00).  Directions are bits 0-3 of `pressed` and are read when P14 is low,
+/// buttons are bits 4-7 and are read when P15 is low.
+#[derive(Debug)]
+pub struct Joypad {
+    select: u8,
+    pressed: u8,
+}
+
+impl_snapshot!(Joypad { select, pressed });
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Joypad {
+            select: 0x30,
+            pressed: 0,
+        }
+    }
+
+    /// The input lines as seen by the cpu, active low
+    fn lines(&self) -> u8 {
+        let mut lines = 0;
+        if self.select & 0x10 == 0 {
+            lines |= self.pressed & 0xf;
+        }
+        if self.select & 0x20 == 0 {
+            lines |= self.pressed >> 4;
+        }
+        !lines & 0xf
+    }
+
+    pub fn set_button(
+        &mut self,
+        button: Button,
+        pressed: bool,
+        int_controller: &mut IntController,
+    ) {
+        let mask = if pressed {
+            self.pressed | button.bit()
+        } else {
+            self.pressed & !button.bit()
+        };
+        self.set_pressed(mask, int_controller);
+    }
+
+    /// Every button at once, with each `Button` as the bit at its position in the enum
+    pub fn pressed(&self) -> u8 {
+        self.pressed
+    }
+
+    pub fn set_pressed(&mut self, pressed: u8, int_controller: &mut IntController) {
+        let old_lines = self.lines();
+        self.pressed = pressed;
+        if old_lines & !self.lines() != 0 {
+            int_controller.raise(Interrupt::Joypad);
+        }
+    }
+
+    pub fn read(&mut self, offset: u8) -> u8 {
+        match offset {
+            0x00 => 0xc0 | self.select | self.lines(),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write(&mut self, offset: u8, val: u8) {
+        match offset {
+            0x00 => self.select = val & 0x30,
+            _ => unreachable!(),
+        }
+    }
+}
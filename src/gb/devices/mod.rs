@@ -2,6 +2,16 @@ use super::EventCycle;
 
 #[macro_use]
 mod macros;
+pub mod apu;
+mod dma;
+mod int_controller;
+mod joypad;
 pub mod ppu;
+mod timer;
 
+pub use apu::{Apu, Sample};
+pub use dma::{Dma, DMA_LEN};
+pub use int_controller::{IntController, Interrupt};
+pub use joypad::{Button, Joypad};
 pub use ppu::{Frame, Ppu};
+pub use timer::Timer;
@@ -0,0 +1,292 @@
+//! A renderer that works dot by dot like the hardware's background fetcher and pixel FIFOs.  It's
+//! slower than drawing a whole line at once, but it gets the length of mode 3 right and picks up
+//! register writes partway through a line at the right pixel.
+
+use std::collections::VecDeque;
+
+use log::*;
+
+use crate::gb::bus::{Bus, Module};
+
+use super::render::{tile_pixel, Sprite, SPRITE_X_OFFSET, WINDOW_X_MAX, WINDOW_X_OFFSET};
+use super::*;
+
+/// Dots spent on the first tile of each line, which is fetched and then thrown away
+const START_DELAY: u8 = 6;
+
+/// Dots the fetcher takes to read a tile number and both bytes of a row, after which it waits for
+/// the background FIFO to empty before pushing the row into it
+const FETCH_DOTS: u8 = 6;
+
+/// Dots taken to fetch a sprite once the background fetcher has finished its current tile
+const SPRITE_FETCH_DOTS: u8 = 6;
+
+const TILE_WIDTH: usize = 8;
+
+const WHITE: Colour = Colour(255, 255, 255);
+
+#[derive(Debug, Clone)]
+struct Fetcher {
+    dot: u8,
+    /// Tile column being fetched, counted from the left of the screen or the window
+    tile_x: u8,
+    window: bool,
+    tile: u8,
+    lo: u8,
+    hi: u8,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct ObjPixel {
+    colour: u8,
+    /// The palette register is only read when the pixel comes out, so keep the whole sprite
+    sprite: Sprite,
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct Fifo {
+    line: u8,
+    window_line: u8,
+    window_triggered: bool,
+
+    /// Sprites on this line in the order they get fetched
+    sprites: Vec<Sprite>,
+    next_sprite: usize,
+    fetching_sprite: Option<Sprite>,
+    sprite_dots: u8,
+
+    fetcher: Fetcher,
+    bg: VecDeque<u8>,
+    obj: VecDeque<Option<ObjPixel>>,
+
+    delay: u8,
+    /// Pixels to throw away before any reach the screen, for fine scrolling
+    discard: u8,
+    x: u8,
+    /// Dots since the start of mode 3
+    dots: u64,
+    window_drawn: bool,
+
+    output: Scanline,
+}
+
+impl Fetcher {
+    fn new(window: bool) -> Self {
+        Fetcher {
+            dot: 0,
+            tile_x: 0,
+            window,
+            tile: 0,
+            lo: 0,
+            hi: 0,
+        }
+    }
+
+    fn ready(&self) -> bool {
+        self.dot >= FETCH_DOTS
+    }
+}
+
+impl Fifo {
+    pub(super) fn new(ppu: &Ppu, bus: &mut Bus) -> Self {
+        Fifo {
+            line: ppu.line,
+            window_line: ppu.window_line,
+            window_triggered: ppu.window_triggered,
+            sprites: ppu.scan_oam(bus),
+            next_sprite: 0,
+            fetching_sprite: None,
+            sprite_dots: 0,
+            fetcher: Fetcher::new(false),
+            bg: VecDeque::with_capacity(TILE_WIDTH),
+            obj: VecDeque::with_capacity(TILE_WIDTH),
+            delay: START_DELAY,
+            discard: ppu.s.scroll_xy.0 % TILE_WIDTH as u8,
+            x: 0,
+            dots: 0,
+            window_drawn: false,
+            output: empty_scanline(),
+        }
+    }
+
+    pub(super) fn done(&self) -> bool {
+        self.x as usize >= FRAME_COLS
+    }
+
+    /// Render up to a number of dots into mode 3, or until the line is finished
+    pub(super) fn run_to(&mut self, dots: u64, s: &Settings, bus: &mut Bus) {
+        while !self.done() && self.dots < dots {
+            self.step(s, bus);
+        }
+    }
+
+    /// How long mode 3 lasts if no registers are written for the rest of the line
+    pub(super) fn predict_length(&self, s: &Settings, bus: &mut Bus) -> u64 {
+        let mut fifo = self.clone();
+        fifo.run_to(u64::MAX, s, bus);
+        fifo.dots
+    }
+
+    /// Finish the line, returning it along with whether the window was drawn on it
+    pub(super) fn finish(mut self, s: &Settings, bus: &mut Bus) -> (Scanline, bool) {
+        self.run_to(u64::MAX, s, bus);
+        trace!(
+            "Rendered line {:?} in {} dots, window drawn: {}",
+            self.line,
+            self.dots,
+            self.window_drawn
+        );
+        (self.output, self.window_drawn)
+    }
+
+    fn step(&mut self, s: &Settings, bus: &mut Bus) {
+        self.dots += 1;
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        if self.fetching_sprite.is_none() {
+            self.check_window(s);
+            self.fetching_sprite = self.next_sprite(s);
+            self.sprite_dots = 0;
+        }
+
+        // Pixels stop coming out while a sprite is fetched, which first waits for the background
+        // fetcher to finish the tile it's on
+        if let Some(sprite) = self.fetching_sprite {
+            if !self.fetcher.ready() {
+                self.fetch_step(s, bus);
+                return;
+            }
+            self.sprite_dots += 1;
+            if self.sprite_dots == SPRITE_FETCH_DOTS {
+                self.load_sprite(sprite, s, bus);
+                self.fetching_sprite = None;
+            }
+            return;
+        }
+
+        self.fetch_step(s, bus);
+        self.pop_pixel(s);
+    }
+
+    /// Switch the fetcher over to the window once the pixel it starts at is reached
+    fn check_window(&mut self, s: &Settings) {
+        let wx = s.window_xy.0;
+        if self.fetcher.window
+            || !s.bg_en
+            || !s.window_en
+            || !self.window_triggered
+            || wx > WINDOW_X_MAX
+            || self.x + WINDOW_X_OFFSET < wx
+        {
+            return;
+        }
+
+        self.bg.clear();
+        self.fetcher = Fetcher::new(true);
+        self.window_drawn = true;
+        // With WX < 7 the window starts partway into its first tile
+        self.discard = WINDOW_X_OFFSET.saturating_sub(wx);
+    }
+
+    fn next_sprite(&mut self, s: &Settings) -> Option<Sprite> {
+        while let Some(sprite) = self.sprites.get(self.next_sprite).copied() {
+            if sprite.x > self.x + SPRITE_X_OFFSET {
+                return None;
+            }
+            self.next_sprite += 1;
+            // Sprites reached while they're disabled are skipped entirely
+            if s.obj_en {
+                return Some(sprite);
+            }
+        }
+        None
+    }
+
+    fn row(&self, s: &Settings) -> u8 {
+        if self.fetcher.window {
+            self.window_line
+        } else {
+            s.scroll_xy.1.wrapping_add(self.line)
+        }
+    }
+
+    fn tile_map_addr(&self, s: &Settings) -> u16 {
+        let (tmap, col) = if self.fetcher.window {
+            (s.window_tmap, self.fetcher.tile_x)
+        } else {
+            // Coarse scrolling is read on every fetch, unlike the fine scroll
+            let col = (s.scroll_xy.0 / TILE_WIDTH as u8).wrapping_add(self.fetcher.tile_x);
+            (s.bg_tmap, col)
+        };
+        tmap.val() + (col & 31) as u16 + (self.row(s) / 8) as u16 * 32
+    }
+
+    fn tile_data_addr(&self, s: &Settings) -> u16 {
+        s.tile_data
+            .map(self.fetcher.tile)
+            .wrapping_add((self.row(s) % 8) as u16 * 2)
+    }
+
+    fn fetch_step(&mut self, s: &Settings, bus: &mut Bus) {
+        if !self.fetcher.ready() {
+            self.fetcher.dot += 1;
+            match self.fetcher.dot {
+                2 => self.fetcher.tile = bus.vram.read(self.tile_map_addr(s)),
+                4 => self.fetcher.lo = bus.vram.read(self.tile_data_addr(s)),
+                6 => self.fetcher.hi = bus.vram.read(self.tile_data_addr(s) + 1),
+                _ => (),
+            }
+        } else if self.bg.is_empty() {
+            let Fetcher { lo, hi, .. } = self.fetcher;
+            self.bg
+                .extend((0..TILE_WIDTH as u8).map(|col| tile_pixel(lo, hi, col)));
+            self.fetcher.tile_x = self.fetcher.tile_x.wrapping_add(1);
+            self.fetcher.dot = 0;
+        }
+    }
+
+    fn load_sprite(&mut self, sprite: Sprite, s: &Settings, bus: &mut Bus) {
+        let pixels = sprite.pixels(bus, s, self.line);
+        // Columns of sprites hanging off the left edge have already gone by
+        let hidden = (self.x + SPRITE_X_OFFSET).saturating_sub(sprite.x) as usize;
+
+        self.obj.resize(TILE_WIDTH, None);
+        for (slot, colour) in pixels.iter().skip(hidden).enumerate() {
+            // Sprites fetched earlier have priority
+            if *colour != 0 && self.obj[slot].is_none() {
+                self.obj[slot] = Some(ObjPixel {
+                    colour: *colour,
+                    sprite,
+                });
+            }
+        }
+    }
+
+    fn pop_pixel(&mut self, s: &Settings) {
+        let bg = match self.bg.pop_front() {
+            Some(bg) => bg,
+            None => return,
+        };
+        if self.discard > 0 {
+            self.discard -= 1;
+            return;
+        }
+        let obj = self.obj.pop_front().flatten();
+
+        // On the DMG the background enable bit blanks it out, without hiding sprites
+        let bg = if s.bg_en { Some(bg) } else { None };
+        let colour = match (obj, bg) {
+            (Some(obj), Some(bg)) if !obj.sprite.behind_bg() || bg == 0 => {
+                obj.sprite.palette(s).map(obj.colour)
+            }
+            (Some(obj), None) => obj.sprite.palette(s).map(obj.colour),
+            (_, Some(bg)) => s.bg_palette.map(bg),
+            (None, None) => WHITE,
+        };
+        self.output[self.x as usize] = colour;
+        self.x += 1;
+    }
+}
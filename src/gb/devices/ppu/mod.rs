@@ -8,16 +8,32 @@ use derive_more::{From, Into};
 
 use crate::compiler::CycleState;
 use crate::gb::bus::Bus;
+use crate::gb::state::{self, Reader, Snapshot};
 
-use super::EventCycle;
+use super::{EventCycle, IntController, Interrupt};
 
+mod fifo;
 mod frame;
 mod render;
 
+use fifo::Fifo;
+
 pub use frame::*;
 
 pub const FRAME_TIME: u64 = 70224;
 
+/// Cycles per scanline, including those in vblank
+const LINE_TIME: u64 = 456;
+
+/// Length of mode 3 with no scrolling, window or sprites, the scanline renderer always uses it
+const MIN_RENDER_TIME: u64 = 172;
+
+/// Cycles left in vblank when the boot ROM hands off to the cartridge
+const POST_BOOT_VBLANK_REMAINING: u64 = 400;
+
+/// Cycles into line 153 after which LY reads as 0
+const LAST_LINE_LY_RESET: u64 = 4;
+
 #[derive(Debug, Copy, Clone)]
 enum Mode {
     Hblank,
@@ -118,13 +134,95 @@ pub struct Ppu {
     frame_started: u64,
 
     line: u8,
-
+    /// Line of the window to draw next, only advances on lines where the window is drawn
+    window_line: u8,
+    /// Whether LY has matched WY this frame, the window isn't drawn until it has
+    window_triggered: bool,
+
+    /// The OR of all the enabled STAT interrupt sources, the interrupt only fires when this rises
+    stat_line: bool,
+    /// Set when a register write may have changed the STAT line or the next LYC match
+    reschedule: bool,
+
+    /// Draw lines dot by dot with a pixel FIFO instead of all at once at the end of mode 3
+    pixel_fifo: bool,
+    /// The line the pixel FIFO renderer is drawing, during mode 3
+    fifo: Option<Fifo>,
+    /// Length of the current or last mode 3, hblank takes up the rest of the line
+    render_length: u64,
+    /// Cycle, register and value of writes made while the pixel FIFO is drawing, which wait for
+    /// the line to be drawn up to them
+    pending_writes: Vec<(u64, u8, u8)>,
+    /// Settings at the start of mode 3, and the cycle, register and value of the writes made to
+    /// rendering registers during it, for the scanline renderer to draw each part of the line with
+    line_settings: Settings,
+    line_writes: Vec<(u64, u8, u8)>,
+
+    /// Set when the LCD is turned on, the first frame after that isn't shown
+    skip_frame: bool,
     current_frame: Box<Frame>,
     completed_frames: VecDeque<Box<Frame>>,
 
     s: Settings,
 }
 
+/// The registers held in `Settings` that lines are drawn with
+const SETTINGS_REGISTERS: [u8; 8] = [0x40, 0x42, 0x43, 0x47, 0x48, 0x49, 0x4a, 0x4b];
+
+impl Settings {
+    fn read(&self, offset: u8) -> u8 {
+        match offset {
+            0x40 => {
+                write_bitfield! {
+                    7 => self.enabled,
+                    6 => self.window_tmap,
+                    5 => self.window_en,
+                    4 => self.tile_data,
+                    3 => self.bg_tmap,
+                    2 => self.obj_size,
+                    1 => self.obj_en,
+                    0 => self.bg_en,
+                }
+            }
+            0x42 => self.scroll_xy.1,
+            0x43 => self.scroll_xy.0,
+            0x47 => self.bg_palette.into(),
+            0x48 => self.o0_palette.into(),
+            0x49 => self.o1_palette.into(),
+            0x4a => self.window_xy.1,
+            0x4b => self.window_xy.0,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Write one of the registers that control how lines are drawn
+    fn write(&mut self, offset: u8, val: u8) {
+        match offset {
+            0x40 => {
+                read_bitfield! {
+                    val,
+                    7 => self.enabled,
+                    6 => self.window_tmap,
+                    5 => self.window_en,
+                    4 => self.tile_data,
+                    3 => self.bg_tmap,
+                    2 => self.obj_size,
+                    1 => self.obj_en,
+                    0 => self.bg_en,
+                }
+            }
+            0x42 => self.scroll_xy.1 = val,
+            0x43 => self.scroll_xy.0 = val,
+            0x47 => self.bg_palette = val.into(),
+            0x48 => self.o0_palette = val.into(),
+            0x49 => self.o1_palette = val.into(),
+            0x4a => self.window_xy.1 = val,
+            0x4b => self.window_xy.0 = val,
+            _ => unreachable!(),
+        }
+    }
+}
+
 impl Mode {
     fn id(self) -> u8 {
         use Mode::*;
@@ -142,66 +240,202 @@ impl Mode {
             Hblank => 200,
             Vblank => 4560,
             Oam => 84,
-            Render => 172,
+            Render => MIN_RENDER_TIME,
         }
     }
 }
 
 impl Ppu {
-    pub fn new(cycles: Rc<CycleState>) -> (Self, EventCycle) {
+    pub fn new(cycles: Rc<CycleState>, pixel_fifo: bool) -> (Self, EventCycle) {
         let current_cycle = cycles.cycle();
         let ppu = Ppu {
             cycles,
-            mode: Mode::Oam,
+            // The LCD starts off
+            mode: Mode::Hblank,
             mode_started: current_cycle,
             frame_started: current_cycle,
             line: 0,
+            window_line: 0,
+            window_triggered: false,
+            stat_line: false,
+            reschedule: false,
+            pixel_fifo,
+            fifo: None,
+            render_length: MIN_RENDER_TIME,
+            pending_writes: Vec::new(),
+            line_settings: Default::default(),
+            line_writes: Vec::new(),
+            skip_frame: false,
             current_frame: Box::new(empty_frame()),
             completed_frames: VecDeque::new(),
             s: Default::default(),
         };
 
-        let limit = ppu.mode_cycle_limit();
+        let next = ppu.next_event();
 
-        (ppu, limit)
+        (ppu, next)
+    }
+
+    /// Create a PPU in the state the boot ROM leaves it in, on the last line of vblank.  The
+    /// cycle count must be at least a frame in.
+    pub fn new_post_boot(cycles: Rc<CycleState>, pixel_fifo: bool) -> (Self, EventCycle) {
+        let (mut ppu, _) = Ppu::new(cycles, pixel_fifo);
+        let current_cycle = ppu.cycles.cycle();
+        ppu.mode = Mode::Vblank;
+        ppu.mode_started = current_cycle - (Mode::Vblank.cycles() - POST_BOOT_VBLANK_REMAINING);
+        // There's no frame in progress, so the first one ends a full frame after vblank does
+        ppu.frame_started = current_cycle + POST_BOOT_VBLANK_REMAINING;
+        ppu.line = 144;
+        ppu.s.enabled = true;
+
+        let next = ppu.next_event();
+
+        (ppu, next)
+    }
+
+    /// Mode 3 gets longer with scrolling, the window and sprites, and hblank shorter to match
+    fn mode_length(&self) -> u64 {
+        match self.mode {
+            Mode::Render => self.render_length,
+            Mode::Hblank => LINE_TIME - Mode::Oam.cycles() - self.render_length,
+            mode => mode.cycles(),
+        }
     }
 
     fn mode_cycle_limit(&self) -> u64 {
-        self.mode_started + self.mode.cycles()
+        self.mode_started + self.mode_length()
     }
 
     fn mode_cycle_limit_hit(&self) -> bool {
         self.cycles.cycle() >= self.mode_cycle_limit()
     }
 
-    pub fn process(&mut self, bus: &mut Bus) -> EventCycle {
-        if !self.mode_cycle_limit_hit() {
-            panic!("Process should not have been called before the event limit was hit")
+    /// Cycle at which LY next becomes LYC, or stops being it, if that happens during the rest of
+    /// vblank.  Outside of vblank LY only changes when a new line starts, which already is an
+    /// event.
+    fn next_lyc_match(&self) -> Option<EventCycle> {
+        let now = self.cycles.cycle();
+        let line_start = |line: u64| self.mode_started + (line - 144) * LINE_TIME;
+        let ly_reset = line_start(153) + LAST_LINE_LY_RESET;
+        let cycle = match (self.mode, self.s.compare_line) {
+            // LY only reads 153 for a moment, the match ends when it moves on to 0
+            (Mode::Vblank, 153) if line_start(153) <= now => ly_reset,
+            (Mode::Vblank, lyc @ 145..=153) => line_start(lyc as u64),
+            (Mode::Vblank, 0) => ly_reset,
+            _ => return None,
+        };
+        Some(cycle).filter(|cycle| *cycle > now)
+    }
+
+    fn next_event(&self) -> EventCycle {
+        if !self.s.enabled {
+            return self.next_frame_end();
         }
+        let limit = self.mode_cycle_limit();
+        self.next_lyc_match().map_or(limit, |lyc| lyc.min(limit))
+    }
 
-        self.end_mode(bus);
+    /// Move on to the next mode if this one is over, and raise any interrupts that are due
+    pub fn process(&mut self, bus: &mut Bus, int_controller: &mut IntController) -> EventCycle {
+        self.apply_pending_writes(bus);
+        if !self.s.enabled {
+            // Frames keep coming at the same rate with the LCD off, they're just blank
+            if self.cycles.cycle() >= self.next_frame_end() {
+                self.completed_frames
+                    .push_back(Box::new(render::white_frame()));
+                self.frame_started += FRAME_TIME;
+            }
+        } else if self.mode_cycle_limit_hit() {
+            self.end_mode(bus);
+            if let (Mode::Vblank, true) = (self.mode, self.s.enabled) {
+                int_controller.raise(Interrupt::Vblank);
+            }
+        }
 
-        self.mode_cycle_limit()
+        self.update_stat(int_controller);
+
+        self.next_event()
     }
 
-    fn start_mode(&mut self, new_mode: Mode, _bus: &mut Bus) {
-        self.mode_started += self.mode.cycles();
+    /// Returns the new next event if a register write may have moved it, after raising any STAT
+    /// interrupt the write caused
+    pub fn take_reschedule(
+        &mut self,
+        bus: &mut Bus,
+        int_controller: &mut IntController,
+    ) -> Option<EventCycle> {
+        if self.reschedule {
+            self.apply_pending_writes(bus);
+            self.reschedule = false;
+            self.update_stat(int_controller);
+            Some(self.next_event())
+        } else {
+            None
+        }
+    }
+
+    /// Draw the line up to each write made during mode 3 before applying it, then work out when
+    /// the line will now finish
+    fn apply_pending_writes(&mut self, bus: &mut Bus) {
+        if self.pending_writes.is_empty() {
+            return;
+        }
+        for (cycle, offset, val) in std::mem::take(&mut self.pending_writes) {
+            if let Some(fifo) = &mut self.fifo {
+                fifo.run_to(cycle - self.mode_started, &self.s, bus);
+            }
+            self.apply_write(offset, val);
+        }
+        if let Some(fifo) = &self.fifo {
+            self.render_length = fifo.predict_length(&self.s, bus);
+        }
+    }
+
+    fn update_stat(&mut self, int_controller: &mut IntController) {
+        let s = &self.s;
+        let mode_source = match self.mode {
+            Mode::Hblank => s.hblank_interrupt,
+            Mode::Vblank => s.vblank_interrupt,
+            Mode::Oam => s.oam_interrupt,
+            Mode::Render => false,
+        };
+        let lyc_source = s.coincidence_interrupt && s.compare_line == self.scanline();
+        let line = s.enabled && (mode_source || lyc_source);
+
+        if line && !self.stat_line {
+            int_controller.raise(Interrupt::Stat);
+        }
+        self.stat_line = line;
+    }
+
+    fn start_mode(&mut self, new_mode: Mode, bus: &mut Bus) {
+        self.mode_started += self.mode_length();
         self.mode = new_mode;
 
+        // Locking OAM and VRAM follows from the mode, see `blocks`
         match self.mode {
-            Mode::Hblank => {
-                // TODO: Unlock OAM and VRAM
-            }
+            Mode::Hblank | Mode::Oam => {}
             Mode::Vblank => {
                 let mut frame = Box::new(empty_frame());
                 std::mem::swap(&mut frame, &mut self.current_frame);
+                if self.skip_frame {
+                    self.skip_frame = false;
+                    frame = Box::new(render::white_frame());
+                }
                 self.completed_frames.push_back(frame);
             }
-            Mode::Oam => {
-                // TODO: Lock OAM
-            }
             Mode::Render => {
-                // TODO: Lock VRAM
+                if self.line == self.s.window_xy.1 {
+                    self.window_triggered = true;
+                }
+                self.render_length = MIN_RENDER_TIME;
+                self.line_settings = self.s;
+                self.line_writes.clear();
+                if self.pixel_fifo && self.s.enabled {
+                    let fifo = Fifo::new(self, bus);
+                    self.render_length = fifo.predict_length(&self.s, bus);
+                    self.fifo = Some(fifo);
+                }
             }
         }
     }
@@ -218,6 +452,8 @@ impl Ppu {
             }
             Mode::Vblank => {
                 self.line = 0;
+                self.window_line = 0;
+                self.window_triggered = false;
                 self.start_mode(Mode::Oam, bus);
                 self.frame_started = self.mode_started;
             }
@@ -225,22 +461,87 @@ impl Ppu {
                 self.start_mode(Mode::Render, bus);
             }
             Mode::Render => {
-                self.current_frame[self.line as usize] = self.render_line(bus);
+                self.current_frame[self.line as usize] = match self.fifo.take() {
+                    Some(fifo) => {
+                        let (line, window_drawn) = fifo.finish(&self.s, bus);
+                        if window_drawn {
+                            self.window_line += 1;
+                        }
+                        line
+                    }
+                    None => self.render_line(bus),
+                };
                 self.start_mode(Mode::Hblank, bus);
             }
         };
     }
 
+    pub fn pixel_fifo(&self) -> bool {
+        self.pixel_fifo
+    }
+
     pub fn scanline(&self) -> u8 {
         match self.mode {
             Mode::Hblank | Mode::Oam | Mode::Render => self.line,
             Mode::Vblank => {
                 let mode_cycles = self.cycles.cycle() - self.mode_started;
-                144 + (mode_cycles / 456) as u8
+                let line = 144 + (mode_cycles / LINE_TIME) as u8;
+                // LY moves on to 0 shortly into the last line, before vblank ends
+                if line == 153 && mode_cycles % LINE_TIME >= LAST_LINE_LY_RESET {
+                    0
+                } else {
+                    line
+                }
             }
         }
     }
 
+    /// Whether the cpu is locked out of an address because the PPU is using it.  OAM is in use
+    /// while sprites are searched for and drawn, and VRAM while lines are drawn.
+    pub fn blocks(&self, addr: u16) -> bool {
+        if !self.s.enabled {
+            return false;
+        }
+        match (addr, self.mode) {
+            (0x8000..=0x9fff, Mode::Render) => true,
+            (0xfe00..=0xfe9f, Mode::Oam) | (0xfe00..=0xfe9f, Mode::Render) => true,
+            _ => false,
+        }
+    }
+
+    /// With the LCD off LY stays at 0 in mode 0.  Whatever was drawn of the frame in progress is
+    /// dropped, and blank frames are put out in its place for as long as it stays off.
+    fn lcd_off(&mut self) {
+        // A frame pushed at the start of vblank is still waiting for the end of the frame to be
+        // taken, so the blank frames start after it
+        if let Mode::Vblank = self.mode {
+            if self.next_frame_end() <= self.mode_cycle_limit() {
+                self.frame_started += FRAME_TIME;
+            }
+        }
+        self.mode = Mode::Hblank;
+        self.line = 0;
+        self.window_line = 0;
+        self.window_triggered = false;
+        self.fifo = None;
+        self.current_frame = Box::new(empty_frame());
+        self.request_reschedule();
+    }
+
+    /// Turning the LCD back on starts a new frame from the top, which isn't shown
+    fn lcd_on(&mut self) {
+        // The blank frame the LCD was putting out is due before the new timeline's first one
+        self.completed_frames
+            .push_back(Box::new(render::white_frame()));
+
+        let current_cycle = self.cycles.cycle();
+        self.mode = Mode::Oam;
+        self.mode_started = current_cycle;
+        self.frame_started = current_cycle;
+        self.skip_frame = true;
+        self.request_reschedule();
+    }
+
     pub fn take_frame(&mut self) -> Option<Box<Frame>> {
         self.completed_frames.pop_front()
     }
@@ -251,18 +552,7 @@ impl Ppu {
 
     pub fn read(&mut self, offset: u8) -> u8 {
         match offset {
-            0x40 => {
-                write_bitfield! {
-                    7 => self.s.enabled,
-                    6 => self.s.window_tmap,
-                    5 => self.s.window_en,
-                    4 => self.s.tile_data,
-                    3 => self.s.bg_tmap,
-                    2 => self.s.obj_size,
-                    1 => self.s.obj_en,
-                    0 => self.s.bg_en,
-                }
-            }
+            0x40 | 0x42 | 0x43 | 0x47..=0x4b => self.s.read(offset),
             0x41 => {
                 self.mode.id()
                     | write_bitfield! {
@@ -273,30 +563,43 @@ impl Ppu {
                         2 => self.s.compare_line == self.scanline(),
                     }
             }
-            0x42 => self.s.scroll_xy.1,
-            0x43 => self.s.scroll_xy.0,
             0x44 => self.scanline(),
             0x45 => self.s.compare_line,
-            0x47 => self.s.bg_palette.into(),
-            0x48 => self.s.o0_palette.into(),
-            0x49 => self.s.o1_palette.into(),
             _ => unreachable!(),
         }
     }
 
+    /// A write that can change the STAT line has to be acted on right away, so stop the cpu to
+    /// let the system pick it up
+    fn request_reschedule(&mut self) {
+        self.reschedule = true;
+        self.cycles.force_stop();
+    }
+
     pub fn write(&mut self, offset: u8, val: u8) {
+        // The pixel FIFO needs the bus to catch up to the write, so it gets applied on reschedule
+        if self.fifo.is_some() && offset != 0x44 {
+            self.pending_writes.push((self.cycles.cycle(), offset, val));
+            self.request_reschedule();
+            return;
+        }
+        self.apply_write(offset, val);
+    }
+
+    fn apply_write(&mut self, offset: u8, val: u8) {
         match offset {
-            0x40 => {
-                read_bitfield! {
-                    val,
-                    7 => self.s.enabled,
-                    6 => self.s.window_tmap,
-                    5 => self.s.window_en,
-                    4 => self.s.tile_data,
-                    3 => self.s.bg_tmap,
-                    2 => self.s.obj_size,
-                    1 => self.s.obj_en,
-                    0 => self.s.bg_en,
+            0x40 | 0x42 | 0x43 | 0x47..=0x4b => {
+                // The scanline renderer draws the whole line at the end of mode 3, so it needs to
+                // know where on the line changes to how it's drawn happened
+                if let (Mode::Render, None, true) = (self.mode, &self.fifo, self.s.enabled) {
+                    self.line_writes.push((self.cycles.cycle(), offset, val));
+                }
+                let was_enabled = self.s.enabled;
+                self.s.write(offset, val);
+                match (was_enabled, self.s.enabled) {
+                    (true, false) => self.lcd_off(),
+                    (false, true) => self.lcd_on(),
+                    _ => (),
                 }
             }
             0x41 => {
@@ -307,13 +610,12 @@ impl Ppu {
                     4 => self.s.vblank_interrupt,
                     3 => self.s.hblank_interrupt,
                 }
+                self.request_reschedule();
+            }
+            0x45 => {
+                self.s.compare_line = val;
+                self.request_reschedule();
             }
-            0x42 => self.s.scroll_xy.1 = val,
-            0x43 => self.s.scroll_xy.0 = val,
-            0x45 => self.s.compare_line = val,
-            0x47 => self.s.bg_palette = val.into(),
-            0x48 => self.s.o0_palette = val.into(),
-            0x49 => self.s.o1_palette = val.into(),
             0x44 => log::warn!(
                 "Attempted to write {:02x} to RO PPU reg 0xff{:02x}",
                 val,
@@ -324,6 +626,105 @@ impl Ppu {
     }
 }
 
+impl Snapshot for Settings {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        for offset in SETTINGS_REGISTERS.iter() {
+            self.read(*offset).save_state(out);
+        }
+        let stat = write_bitfield! {
+            6 => self.coincidence_interrupt,
+            5 => self.oam_interrupt,
+            4 => self.vblank_interrupt,
+            3 => self.hblank_interrupt,
+        };
+        stat.save_state(out);
+        self.compare_line.save_state(out);
+    }
+
+    fn load_state(&mut self, r: &mut Reader<'_>) -> Result<(), state::Error> {
+        for offset in SETTINGS_REGISTERS.iter() {
+            let mut val = 0u8;
+            val.load_state(r)?;
+            self.write(*offset, val);
+        }
+        let mut stat = 0u8;
+        stat.load_state(r)?;
+        read_bitfield! {
+            stat,
+            6 => self.coincidence_interrupt,
+            5 => self.oam_interrupt,
+            4 => self.vblank_interrupt,
+            3 => self.hblank_interrupt,
+        }
+        self.compare_line.load_state(r)
+    }
+}
+
+impl Snapshot for Mode {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        self.id().save_state(out)
+    }
+
+    fn load_state(&mut self, r: &mut Reader<'_>) -> Result<(), state::Error> {
+        let mut id = 0u8;
+        id.load_state(r)?;
+        *self = match id {
+            0 => Mode::Hblank,
+            1 => Mode::Vblank,
+            2 => Mode::Oam,
+            3 => Mode::Render,
+            _ => return Err(state::Error::Invalid("PPU mode")),
+        };
+        Ok(())
+    }
+}
+
+/// Frames end when vblank does, so states taken between them never have a line partway through
+/// being drawn, nor any of the frame in progress drawn yet
+impl Snapshot for Ppu {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        self.pixel_fifo.save_state(out);
+        self.mode.save_state(out);
+        self.mode_started.save_state(out);
+        self.frame_started.save_state(out);
+        self.line.save_state(out);
+        self.window_line.save_state(out);
+        self.window_triggered.save_state(out);
+        self.stat_line.save_state(out);
+        self.reschedule.save_state(out);
+        self.render_length.save_state(out);
+        self.skip_frame.save_state(out);
+        self.s.save_state(out);
+    }
+
+    /// The renderers time mode 3 differently, so states only load into the one they came from
+    fn load_state(&mut self, r: &mut Reader<'_>) -> Result<(), state::Error> {
+        let mut pixel_fifo = false;
+        pixel_fifo.load_state(r)?;
+        if pixel_fifo != self.pixel_fifo {
+            return Err(state::Error::WrongRenderer(pixel_fifo));
+        }
+        self.mode.load_state(r)?;
+        self.mode_started.load_state(r)?;
+        self.frame_started.load_state(r)?;
+        self.line.load_state(r)?;
+        self.window_line.load_state(r)?;
+        self.window_triggered.load_state(r)?;
+        self.stat_line.load_state(r)?;
+        self.reschedule.load_state(r)?;
+        self.render_length.load_state(r)?;
+        self.skip_frame.load_state(r)?;
+        self.s.load_state(r)?;
+
+        self.fifo = None;
+        self.pending_writes.clear();
+        self.line_writes.clear();
+        self.current_frame = Box::new(empty_frame());
+        self.completed_frames.clear();
+        Ok(())
+    }
+}
+
 fn to_flag(val: bool, idx: usize) -> u8 {
     if val {
         1u8 << idx
@@ -335,3 +736,39 @@ fn to_flag(val: bool, idx: usize) -> u8 {
 fn from_flag(val: u8, idx: usize) -> bool {
     (val & (1u8 << idx)) != 0
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lyc_153_match_ends_when_ly_resets() {
+        let cycles = Rc::new(CycleState::new());
+        let mut ints = IntController::new(cycles.clone());
+        ints.write(0xff, 0x02);
+        let (mut ppu, _) = Ppu::new(cycles.clone(), false);
+        cycles.advance(FRAME_TIME);
+        ppu.mode = Mode::Vblank;
+        ppu.mode_started = cycles.cycle() - 8 * LINE_TIME;
+        ppu.s.enabled = true;
+        ppu.s.coincidence_interrupt = true;
+        ppu.s.compare_line = 153;
+
+        let line_153 = cycles.cycle() + LINE_TIME;
+        assert_eq!(ppu.next_lyc_match(), Some(line_153));
+        cycles.advance(LINE_TIME);
+        ppu.update_stat(&mut ints);
+        assert_eq!(ints.acknowledge(), Some(Interrupt::Stat));
+
+        assert_eq!(ppu.next_lyc_match(), Some(line_153 + LAST_LINE_LY_RESET));
+        cycles.advance(LAST_LINE_LY_RESET);
+        ppu.update_stat(&mut ints);
+        assert!(!ppu.stat_line);
+        assert_eq!(ppu.next_lyc_match(), None);
+
+        // With the line low, matching LY=0 on the same line is a new rising edge
+        ppu.s.compare_line = 0;
+        ppu.update_stat(&mut ints);
+        assert_eq!(ints.acknowledge(), Some(Interrupt::Stat));
+    }
+}
@@ -1,30 +1,288 @@
+use std::ops::Range;
+
 use log::*;
 
 use crate::gb::bus::{Bus, Module};
 
 use super::*;
 
+/// Colour indices of a line before going through a palette
+type IndexLine = [u8; FRAME_COLS];
+
+/// Columns of a line and the settings they're drawn with
+type Segment = (Range<usize>, Settings);
+
+/// Dots into mode 3 before the first pixel is drawn, without fine scrolling or sprites
+const FIRST_PIXEL_DOT: u64 = 12;
+
+const OAM_BASE: u16 = 0xfe00;
+const OAM_ENTRIES: u16 = 40;
+const MAX_LINE_SPRITES: usize = 10;
+
+/// Sprites are positioned offset from the top left of the screen, so that they can be partially
+/// offscreen
+const SPRITE_Y_OFFSET: u8 = 16;
+pub(super) const SPRITE_X_OFFSET: u8 = 8;
+
+/// WX is the window's screen position plus 7, anything past the right edge hides it
+pub(super) const WINDOW_X_OFFSET: u8 = 7;
+pub(super) const WINDOW_X_MAX: u8 = FRAME_COLS as u8 + WINDOW_X_OFFSET - 1;
+
+#[derive(Debug, Copy, Clone)]
+pub(super) struct Sprite {
+    y: u8,
+    pub(super) x: u8,
+    tile: u8,
+    flags: u8,
+}
+
+impl Sprite {
+    fn read(bus: &mut Bus, idx: u16) -> Self {
+        let addr = OAM_BASE + idx * 4;
+        let oam = &mut bus.oam;
+        Sprite {
+            y: oam.read(addr),
+            x: oam.read(addr + 1),
+            tile: oam.read(addr + 2),
+            flags: oam.read(addr + 3),
+        }
+    }
+
+    pub(super) fn behind_bg(self) -> bool {
+        self.flags & 0x80 != 0
+    }
+
+    fn y_flip(self) -> bool {
+        self.flags & 0x40 != 0
+    }
+
+    fn x_flip(self) -> bool {
+        self.flags & 0x20 != 0
+    }
+
+    pub(super) fn palette(self, s: &Settings) -> BwPalette {
+        if self.flags & 0x10 != 0 {
+            s.o1_palette
+        } else {
+            s.o0_palette
+        }
+    }
+
+    /// The colour indices of the sprite's 8 pixels on a line, left to right on the screen
+    pub(super) fn pixels(self, bus: &mut Bus, s: &Settings, line: u8) -> [u8; 8] {
+        let height = s.obj_size.val().1;
+        let mut row = line + SPRITE_Y_OFFSET - self.y;
+        if self.y_flip() {
+            row = height - 1 - row;
+        }
+        let tile = if height == 16 {
+            self.tile & 0xfe
+        } else {
+            self.tile
+        };
+        // Sprites always use the 0x8000 tile data, with 8x16 sprites covering two tiles
+        let addr = TileData::Lo.map(tile) + row as u16 * 2;
+        let b0 = bus.vram.read(addr);
+        let b1 = bus.vram.read(addr + 1);
+
+        let mut pixels = [0; 8];
+        for (col, px) in pixels.iter_mut().enumerate() {
+            let bit = if self.x_flip() { 7 - col } else { col };
+            *px = tile_pixel(b0, b1, bit as u8);
+        }
+        pixels
+    }
+}
+
+/// The colour index of a pixel in a row of a tile, column 0 is the leftmost
+pub(super) fn tile_pixel(b0: u8, b1: u8, col: u8) -> u8 {
+    (if b0 & (0x80u8 >> col) != 0 { 1 } else { 0 }) | if b1 & (0x80u8 >> col) != 0 { 2 } else { 0 }
+}
+
 impl Ppu {
-    pub(super) fn render_line(&self, bus: &mut Bus) -> Scanline {
+    pub(super) fn render_line(&mut self, bus: &mut Bus) -> Scanline {
         if !self.s.enabled {
             return white_line();
         }
 
-        let bg = self.render_background(bus);
+        let segments = self.line_segments();
+
+        let mut line = white_line();
+        let mut bg = [0; FRAME_COLS];
+        let mut window_drawn = false;
+        for (cols, s) in &segments {
+            // On the DMG the background enable bit turns off the window too
+            if !s.bg_en {
+                continue;
+            }
+            self.render_background(bus, s, &mut bg, cols.clone());
+            window_drawn |= self.render_window(bus, s, &mut bg, cols.clone());
+            for x in cols.clone() {
+                line[x] = s.bg_palette.map(bg[x]);
+            }
+        }
+        // The window has its own line counter that only moves on lines it was drawn on, so hiding
+        // it partway down the screen and showing it again carries on from where it left off
+        if window_drawn {
+            self.window_line += 1;
+        }
 
-        bg
+        self.render_sprites(bus, &segments, &bg, &mut line);
+
+        line
     }
 
-    fn render_background(&self, bus: &mut Bus) -> Scanline {
-        let s = &self.s;
+    /// Split the line up by the writes made to rendering registers during mode 3.  Pixels are
+    /// taken to come out one per dot, so the extra time taken by fine scrolling, the window and
+    /// sprites moves changes a little to the left of where they'd show up on hardware.
+    fn line_segments(&self) -> Vec<Segment> {
+        let mut s = self.line_settings;
+        let mut starts = vec![(0, s)];
+        for &(cycle, offset, val) in &self.line_writes {
+            let dots = cycle - self.mode_started;
+            let col = (dots.saturating_sub(FIRST_PIXEL_DOT) as usize).min(FRAME_COLS);
+            s.write(offset, val);
+            match starts.last_mut() {
+                Some(last) if last.0 == col => last.1 = s,
+                _ => starts.push((col, s)),
+            }
+        }
 
-        if !s.bg_en {
-            return white_line();
+        let ends = starts
+            .iter()
+            .skip(1)
+            .map(|(col, _)| *col)
+            .chain(Some(FRAME_COLS));
+        starts
+            .iter()
+            .zip(ends)
+            .map(|((start, s), end)| (*start..end, *s))
+            .collect()
+    }
+
+    /// The sprites on the current line, in the order they're drawn with.  Only the first 10 in OAM
+    /// are found, and on the DMG the one with the lowest x coordinate wins, then the one earliest
+    /// in OAM.
+    pub(super) fn scan_oam(&self, bus: &mut Bus) -> Vec<Sprite> {
+        let height = self.s.obj_size.val().1;
+        let line = self.line + SPRITE_Y_OFFSET;
+
+        let mut sprites: Vec<Sprite> = (0..OAM_ENTRIES)
+            .map(|idx| Sprite::read(bus, idx))
+            .filter(|sprite| line >= sprite.y && (line as u16) < sprite.y as u16 + height as u16)
+            .take(MAX_LINE_SPRITES)
+            .collect();
+        // Stable, so ties stay in OAM order
+        sprites.sort_by_key(|sprite| sprite.x);
+        sprites
+    }
+
+    fn render_sprites(
+        &self,
+        bus: &mut Bus,
+        segments: &[Segment],
+        bg: &IndexLine,
+        line: &mut Scanline,
+    ) {
+        if !segments.iter().any(|(_, s)| s.obj_en) {
+            return;
+        }
+        let sprites = self.scan_oam(bus);
+
+        trace!("Rendering sprites on line {:?}: {:?}", self.line, sprites);
+
+        // A higher priority sprite takes the pixel even when the background ends up drawn over it
+        let mut claimed = [false; FRAME_COLS];
+
+        for sprite in sprites {
+            let pixels = sprite.pixels(bus, &self.s, self.line);
+
+            for (col, colour_idx) in pixels.iter().enumerate() {
+                let x = sprite.x as usize + col;
+                if x < SPRITE_X_OFFSET as usize || x >= FRAME_COLS + SPRITE_X_OFFSET as usize {
+                    continue;
+                }
+                let x = x - SPRITE_X_OFFSET as usize;
+                let s = settings_at(segments, x);
+                if claimed[x] || !s.obj_en {
+                    continue;
+                }
+
+                let colour = match sprite.palette(s).map_obj(*colour_idx) {
+                    Some(colour) => colour,
+                    None => continue,
+                };
+                claimed[x] = true;
+                if sprite.behind_bg() && bg[x] != 0 {
+                    continue;
+                }
+                line[x] = colour;
+            }
+        }
+    }
+
+    /// Draw the window over the background in some columns, returning whether any of it was drawn
+    fn render_window(
+        &self,
+        bus: &mut Bus,
+        s: &Settings,
+        line: &mut IndexLine,
+        cols: Range<usize>,
+    ) -> bool {
+        let (wx, _) = s.window_xy;
+        if !s.window_en || !self.window_triggered || wx > WINDOW_X_MAX {
+            return false;
         }
 
         let vram = &mut bus.vram;
 
-        let mut line = empty_scanline();
+        let tmap = s.window_tmap.val();
+        let tdata = s.tile_data;
+
+        let y = self.window_line;
+        // WX is offset by 7, and with WX < 7 the window starts partway into its first tile
+        let start = wx.saturating_sub(WINDOW_X_OFFSET) as usize;
+        let skipped = WINDOW_X_OFFSET.saturating_sub(wx);
+        if cols.end <= start {
+            return false;
+        }
+
+        trace!(
+            "Rendering window line {:?} on line {:?}, position: {:?}, tile data: {:?}, tile map: {:?}",
+            y,
+            self.line,
+            s.window_xy,
+            s.tile_data,
+            s.window_tmap,
+        );
+
+        for i in start.max(cols.start)..cols.end {
+            let x = (i - start) as u8 + skipped;
+
+            let tile_idx = (x / 8) as u16 + (y / 8) as u16 * 32;
+            let tile_val = vram.read(tmap + tile_idx);
+            let tile_addr = tdata.map(tile_val);
+
+            let (col, row) = (x % 8, y % 8);
+
+            let addr = tile_addr.wrapping_add(row as u16 * 2);
+            let b0 = vram.read(addr);
+            let b1 = vram.read(addr + 1);
+
+            line[i] = tile_pixel(b0, b1, col);
+        }
+
+        true
+    }
+
+    fn render_background(
+        &self,
+        bus: &mut Bus,
+        s: &Settings,
+        line: &mut IndexLine,
+        cols: Range<usize>,
+    ) {
+        let vram = &mut bus.vram;
 
         let tmap = s.bg_tmap.val();
         let tdata = s.tile_data;
@@ -40,7 +298,7 @@ impl Ppu {
             s.bg_palette
         );
 
-        for (i, px) in line.iter_mut().enumerate() {
+        for i in cols {
             let x = s.scroll_xy.0.wrapping_add(i as u8);
 
             let tile_xy = (x / 8, y / 8);
@@ -56,10 +314,9 @@ impl Ppu {
             let b0 = vram.read(addr);
             let b1 = vram.read(addr + 1);
 
-            let colour_idx = if b0 & (0x80u8 >> col) != 0 { 1 } else { 0 }
-                | if b1 & (0x80u8 >> col) != 0 { 2 } else { 0 };
+            let colour_idx = tile_pixel(b0, b1, col);
 
-            *px = s.bg_palette.map(colour_idx);
+            line[i] = colour_idx;
 
             trace!(
                 "bg px {:3?} => {:3?}, tile {:2?} ({:#06x?}) => {:02x?} ({:#06x?}), offset: {:1?} colour: {}",
@@ -73,12 +330,18 @@ impl Ppu {
                 colour_idx,
             )
         }
-
-        line
     }
 }
 
-fn white_line() -> Scanline {
+fn settings_at(segments: &[Segment], x: usize) -> &Settings {
+    let (_, s) = segments
+        .iter()
+        .find(|(cols, _)| cols.contains(&x))
+        .expect("Segments cover the whole line");
+    s
+}
+
+pub(super) fn white_line() -> Scanline {
     let mut line = empty_scanline();
 
     for col in line.iter_mut() {
@@ -87,3 +350,32 @@ fn white_line() -> Scanline {
 
     line
 }
+
+pub(super) fn white_frame() -> Frame {
+    [white_line(); FRAME_ROWS]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn splits_line_at_mid_render_writes() {
+        let cycles = Rc::new(CycleState::new());
+        let (mut ppu, _) = Ppu::new(cycles, false);
+        ppu.mode_started = 0;
+        ppu.line_writes = vec![
+            (FIRST_PIXEL_DOT + 20, 0x43, 5),
+            (FIRST_PIXEL_DOT + 20, 0x47, 0xe4),
+            (FIRST_PIXEL_DOT + 400, 0x42, 1),
+        ];
+
+        let segments = ppu.line_segments();
+        let cols: Vec<_> = segments.iter().map(|(cols, _)| cols.clone()).collect();
+        assert_eq!(cols, vec![0..20, 20..FRAME_COLS, FRAME_COLS..FRAME_COLS]);
+        assert_eq!(segments[0].1.scroll_xy, (0, 0));
+        assert_eq!(segments[1].1.scroll_xy, (5, 0));
+        assert_eq!(u8::from(segments[1].1.bg_palette), 0xe4);
+        assert_eq!(settings_at(&segments, 159).scroll_xy, (5, 0));
+    }
+}
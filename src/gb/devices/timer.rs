@@ -0,0 +1,289 @@
+use std::rc::Rc;
+
+use crate::compiler::CycleState;
+use crate::gb::state::impl_snapshot;
+
+use super::{EventCycle, IntController, Interrupt};
+
+/// Cycles between TIMA overflowing and TMA being reloaded/the interrupt being requested
+const RELOAD_DELAY: u64 = 4;
+
+/// The DIV/TIMA/TMA/TAC registers.  Nothing here ticks: DIV is the upper byte of a system counter
+/// derived from the cycle count, and TIMA is brought up to date by counting the falling edges of
+/// the counter bit selected by TAC whenever it's accessed.
+pub struct Timer {
+    cycles: Rc<CycleState>,
+    /// Cycle at which the system counter was last 0
+    counter_base: u64,
+    /// Cycle up to which `tima` is up to date
+    synced: u64,
+    reload_cycle: Option<u64>,
+    overflowed: bool,
+    reschedule: bool,
+
+    tima: u8,
+    tma: u8,
+    tac: u8,
+}
+
+impl_snapshot!(Timer {
+    counter_base,
+    synced,
+    reload_cycle,
+    overflowed,
+    reschedule,
+    tima,
+    tma,
+    tac,
+});
+
+impl Timer {
+    pub fn new(cycles: Rc<CycleState>) -> Self {
+        let current_cycle = cycles.cycle();
+        Timer {
+            cycles,
+            counter_base: current_cycle,
+            synced: current_cycle,
+            reload_cycle: None,
+            overflowed: false,
+            reschedule: false,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+        }
+    }
+
+    /// Set the system counter that DIV is the upper byte of, for starting in the state the boot
+    /// ROM leaves it in
+    pub fn set_counter(&mut self, counter: u64) {
+        let now = self.cycles.cycle();
+        self.counter_base = now - counter;
+        self.synced = now;
+    }
+
+    fn enabled(&self) -> bool {
+        self.tac & 0x4 != 0
+    }
+
+    /// Period of the counter bit selected by TAC, a falling edge happens once every period
+    fn period(&self) -> u64 {
+        match self.tac & 0x3 {
+            0 => 1024,
+            1 => 16,
+            2 => 64,
+            3 => 256,
+            _ => unreachable!(),
+        }
+    }
+
+    fn counter(&self, cycle: u64) -> u64 {
+        cycle - self.counter_base
+    }
+
+    /// The input to the falling edge detector that increments TIMA
+    fn timer_input(&self, cycle: u64) -> bool {
+        self.enabled() && self.counter(cycle) & (self.period() / 2) != 0
+    }
+
+    fn edges_between(&self, start: u64, end: u64) -> u64 {
+        let period = self.period();
+        self.counter(end) / period - self.counter(start) / period
+    }
+
+    fn nth_edge_after(&self, start: u64, n: u64) -> u64 {
+        let period = self.period();
+        let first = (self.counter(start) / period + 1) * period;
+        self.counter_base + first + (n - 1) * period
+    }
+
+    fn overflow(&mut self, cycle: u64, now: u64) {
+        self.tima = 0;
+        let reload = cycle + RELOAD_DELAY;
+        if reload > now {
+            self.reload_cycle = Some(reload);
+            self.synced = now;
+        } else {
+            self.tima = self.tma;
+            self.overflowed = true;
+            self.synced = reload;
+        }
+    }
+
+    fn sync(&mut self) {
+        let now = self.cycles.cycle();
+        if let Some(reload) = self.reload_cycle {
+            if reload > now {
+                return;
+            }
+            self.reload_cycle = None;
+            self.tima = self.tma;
+            self.overflowed = true;
+            self.synced = reload;
+        }
+
+        if !self.enabled() {
+            self.synced = now;
+            return;
+        }
+
+        loop {
+            let edges = self.edges_between(self.synced, now);
+            let room = 0x100 - self.tima as u64;
+            if edges < room {
+                self.tima += edges as u8;
+                self.synced = now;
+                return;
+            }
+            let overflow = self.nth_edge_after(self.synced, room);
+            self.overflow(overflow, now);
+            if self.reload_cycle.is_some() {
+                return;
+            }
+        }
+    }
+
+    /// Increment TIMA outside of the regular schedule, caused by a falling edge from a register
+    /// write
+    fn tick(&mut self) {
+        let now = self.cycles.cycle();
+        if self.tima == 0xff {
+            self.overflow(now, now);
+        } else {
+            self.tima += 1;
+        }
+    }
+
+    /// The cycle at which the next timer interrupt will be requested
+    fn next_event(&self) -> Option<EventCycle> {
+        if let Some(reload) = self.reload_cycle {
+            return Some(reload);
+        }
+        if !self.enabled() {
+            return None;
+        }
+        let room = 0x100 - self.tima as u64;
+        Some(self.nth_edge_after(self.synced, room) + RELOAD_DELAY)
+    }
+
+    pub fn process(&mut self, int_controller: &mut IntController) -> Option<EventCycle> {
+        self.sync();
+        if self.overflowed {
+            self.overflowed = false;
+            int_controller.raise(Interrupt::Timer);
+        }
+        self.next_event()
+    }
+
+    /// If a register write has moved the next overflow, returns the new event cycle to schedule
+    pub fn take_reschedule(&mut self) -> Option<Option<EventCycle>> {
+        if self.reschedule {
+            self.reschedule = false;
+            Some(self.next_event())
+        } else {
+            None
+        }
+    }
+
+    pub fn read(&mut self, offset: u8) -> u8 {
+        self.sync();
+        match offset {
+            0x04 => (self.counter(self.cycles.cycle()) >> 8) as u8,
+            0x05 => self.tima,
+            0x06 => self.tma,
+            0x07 => self.tac | 0xf8,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write(&mut self, offset: u8, val: u8) {
+        self.sync();
+        let now = self.cycles.cycle();
+        match offset {
+            0x04 => {
+                if self.timer_input(now) {
+                    self.tick();
+                }
+                self.counter_base = now;
+                self.synced = now;
+            }
+            0x05 => {
+                // Writing during the reload delay cancels the reload and the interrupt
+                self.reload_cycle = None;
+                self.tima = val;
+            }
+            // TMA is only read when TIMA reloads, which doesn't move the next event
+            0x06 => {
+                self.tma = val;
+                return;
+            }
+            0x07 => {
+                let old_input = self.timer_input(now);
+                self.tac = val & 0x7;
+                if old_input && !self.timer_input(now) {
+                    self.tick();
+                }
+            }
+            _ => unreachable!(),
+        }
+        self.reschedule = true;
+        // The next overflow may have moved earlier, so let the system reschedule it
+        self.cycles.force_stop();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn setup() -> (Rc<CycleState>, Timer, IntController) {
+        let cycles = Rc::new(CycleState::new());
+        let timer = Timer::new(cycles.clone());
+        let int_controller = IntController::new(cycles.clone());
+        (cycles, timer, int_controller)
+    }
+
+    #[test]
+    fn div_counts_from_reset() {
+        let (cycles, mut timer, _) = setup();
+        cycles.advance(0x1234);
+        assert_eq!(timer.read(0x04), 0x12);
+        timer.write(0x04, 0xab);
+        assert_eq!(timer.read(0x04), 0x00);
+        cycles.advance(0x300);
+        assert_eq!(timer.read(0x04), 0x03);
+    }
+
+    #[test]
+    fn tima_overflow_reloads_and_interrupts() {
+        let (cycles, mut timer, mut ints) = setup();
+        ints.write(0xff, 0x04);
+        timer.write(0x06, 0xf0);
+        timer.write(0x05, 0xfe);
+        timer.write(0x07, 0x05);
+
+        let next = timer.take_reschedule().unwrap().unwrap();
+        assert_eq!(next, 32 + RELOAD_DELAY);
+        // Changing the reload value leaves the overflow where it was
+        timer.write(0x06, 0xf0);
+        assert!(timer.take_reschedule().is_none());
+
+        cycles.advance(32);
+        assert_eq!(timer.read(0x05), 0x00);
+        cycles.advance(RELOAD_DELAY);
+        assert_eq!(timer.process(&mut ints), Some(next + 16 * 16));
+        assert_eq!(timer.read(0x05), 0xf0);
+        assert!(ints.pending());
+    }
+
+    #[test]
+    fn div_write_falling_edge() {
+        let (cycles, mut timer, _) = setup();
+        timer.write(0x07, 0x05);
+        cycles.advance(8);
+        timer.write(0x04, 0);
+        assert_eq!(timer.read(0x05), 1);
+        cycles.advance(4);
+        timer.write(0x04, 0);
+        assert_eq!(timer.read(0x05), 1);
+    }
+}
@@ -2,6 +2,7 @@ use std::cmp::Reverse;
 use std::collections::BinaryHeap;
 use std::rc::Rc;
 
+use super::state::{self, Reader, Snapshot};
 use super::CycleState;
 
 pub type EventCycle = u64;
@@ -9,6 +10,9 @@ pub type EventCycle = u64;
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 pub enum EventSource {
     Ppu,
+    Timer,
+    Dma,
+    Apu,
     FrameEnd,
 }
 
@@ -38,6 +42,15 @@ impl EventManager {
         self.update_limit();
     }
 
+    pub fn remove_events(&mut self, source: EventSource) {
+        self.events = self
+            .events
+            .drain()
+            .filter(|entry| entry.0.source != source)
+            .collect();
+        self.update_limit();
+    }
+
     pub fn update_limit(&self) {
         let new_limit = self
             .events
@@ -46,6 +59,10 @@ impl EventManager {
         self.cycles.set_hard_limit(new_limit);
     }
 
+    pub fn next_event(&self) -> Option<EventCycle> {
+        self.events.peek().map(|front| front.0.cycle)
+    }
+
     pub fn get_events(&mut self) -> impl Iterator<Item = EventSource> {
         let mut ret = vec![];
         let current_cycle = self.cycles.cycle();
@@ -69,3 +86,53 @@ impl Iterator for EventIter {
         self.0.pop()
     }
 }
+
+impl Snapshot for EventSource {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        (*self as u8).save_state(out)
+    }
+
+    fn load_state(&mut self, r: &mut Reader<'_>) -> Result<(), state::Error> {
+        use EventSource::*;
+        let mut id = 0u8;
+        id.load_state(r)?;
+        *self = match id {
+            0 => Ppu,
+            1 => Timer,
+            2 => Dma,
+            3 => Apu,
+            4 => FrameEnd,
+            _ => return Err(state::Error::Invalid("event source")),
+        };
+        Ok(())
+    }
+}
+
+impl Snapshot for EventManager {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        let mut events: Vec<_> = self.events.iter().map(|entry| entry.0).collect();
+        events.sort();
+        (events.len() as u32).save_state(out);
+        for entry in events {
+            entry.cycle.save_state(out);
+            entry.source.save_state(out);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut Reader<'_>) -> Result<(), state::Error> {
+        let mut len = 0u32;
+        len.load_state(r)?;
+        self.events.clear();
+        for _ in 0..len {
+            let mut entry = EventEntry {
+                cycle: 0,
+                source: EventSource::FrameEnd,
+            };
+            entry.cycle.load_state(r)?;
+            entry.source.load_state(r)?;
+            self.events.push(Reverse(entry));
+        }
+        self.update_limit();
+        Ok(())
+    }
+}
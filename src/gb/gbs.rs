@@ -0,0 +1,266 @@
+//! GBS files, soundtracks ripped from games as just the sound driver and music data.  They're
+//! played by building a ROM image with the data at its load address and a small driver below it,
+//! which calls the file's init routine for the chosen song and then its play routine from the
+//! vblank or timer interrupt.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use quick_error::quick_error;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        Io(err: io::Error) {
+            from()
+            display("Failed to read GBS file: {}", err)
+        }
+        Truncated(len: usize) {
+            display("GBS file is only {:#x} bytes, too short to contain a header", len)
+        }
+        BadMagic {
+            display("Not a GBS file")
+        }
+        UnsupportedVersion(version: u8) {
+            display("Unsupported GBS version {}", version)
+        }
+        BadLoadAddress(addr: u16) {
+            display("GBS load address {:#06x} is outside of 0x0400-0x7fff", addr)
+        }
+        TooLarge(len: usize) {
+            display("GBS data is {:#x} bytes, too large to fit in a cartridge", len)
+        }
+        BadSong(song: u8, songs: u8) {
+            display("Song {} is out of range, the file has {}", song, songs)
+        }
+    }
+}
+
+const HEADER_LEN: usize = 0x70;
+
+/// The driver and the generated cartridge header have to fit below the data
+const MIN_LOAD_ADDR: u16 = 0x400;
+
+const MAX_ROM_SIZE: usize = 0x80_0000;
+
+/// Where the driver starts once the entry point at 0x100 has jumped over the cartridge header
+const DRIVER_ADDR: u16 = 0x150;
+
+const VBLANK_VECTOR: u16 = 0x40;
+const TIMER_VECTOR: u16 = 0x50;
+
+#[derive(Debug, Clone)]
+pub struct GbsHeader {
+    pub songs: u8,
+    /// The song to play by default, numbered from 1
+    pub first_song: u8,
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    pub stack_pointer: u16,
+    pub timer_modulo: u8,
+    /// When bit 2 is set the play routine is called on timer interrupts, otherwise on vblank
+    pub timer_control: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+}
+
+pub struct Gbs {
+    pub header: GbsHeader,
+    data: Vec<u8>,
+}
+
+impl GbsHeader {
+    fn parse(file: &[u8]) -> Result<Self, Error> {
+        if file.len() < HEADER_LEN {
+            return Err(Error::Truncated(file.len()));
+        }
+        if &file[0..3] != b"GBS" {
+            return Err(Error::BadMagic);
+        }
+        if file[3] != 1 {
+            return Err(Error::UnsupportedVersion(file[3]));
+        }
+
+        let word = |offset: usize| u16::from_le_bytes([file[offset], file[offset + 1]]);
+        let string = |offset: usize| {
+            file[offset..offset + 0x20]
+                .iter()
+                .take_while(|b| **b != 0)
+                .map(|b| *b as char)
+                .collect()
+        };
+
+        let header = GbsHeader {
+            songs: file[4],
+            first_song: file[5],
+            load_addr: word(0x06),
+            init_addr: word(0x08),
+            play_addr: word(0x0a),
+            stack_pointer: word(0x0c),
+            timer_modulo: file[0x0e],
+            timer_control: file[0x0f],
+            title: string(0x10),
+            author: string(0x30),
+            copyright: string(0x50),
+        };
+        if header.load_addr < MIN_LOAD_ADDR || header.load_addr >= 0x8000 {
+            return Err(Error::BadLoadAddress(header.load_addr));
+        }
+        Ok(header)
+    }
+
+    fn uses_timer(&self) -> bool {
+        self.timer_control & 0x4 != 0
+    }
+
+    /// TMA and TAC to set the timer up with.  The CGB double speed bit makes the timer tick twice
+    /// as fast, which is matched by halving the ticks between overflows, rounding odd counts up.
+    fn timer_registers(&self) -> (u8, u8) {
+        let tma = match self.timer_control & 0x80 != 0 {
+            true => {
+                let period = 256 - self.timer_modulo as u16;
+                (256 - (period + 1) / 2) as u8
+            }
+            false => self.timer_modulo,
+        };
+        (tma, self.timer_control & 0x7)
+    }
+}
+
+impl Gbs {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let file = fs::read(path)?;
+        let header = GbsHeader::parse(&file)?;
+        Ok(Gbs {
+            header,
+            data: file[HEADER_LEN..].to_vec(),
+        })
+    }
+
+    /// Build the ROM image that plays a song, numbered from 1.  It's sized to a whole number of
+    /// 32KiB ROM sizes, with the cartridge header left blank.
+    pub fn rom(&self, song: u8) -> Result<Vec<u8>, Error> {
+        let h = &self.header;
+        if song == 0 || song > h.songs {
+            return Err(Error::BadSong(song, h.songs));
+        }
+
+        let end = h.load_addr as usize + self.data.len();
+        if end > MAX_ROM_SIZE {
+            return Err(Error::TooLarge(self.data.len()));
+        }
+        let mut rom = vec![0xff; end.next_power_of_two().max(0x8000)];
+        rom[h.load_addr as usize..end].copy_from_slice(&self.data);
+
+        // RST instructions in GBS code jump relative to the load address
+        for vector in (0..VBLANK_VECTOR).step_by(8) {
+            write_code(&mut rom, vector, &jp(h.load_addr + vector));
+        }
+        let play = [&call(h.play_addr)[..], &[0xd9]].concat(); // reti
+        write_code(&mut rom, VBLANK_VECTOR, &play);
+        write_code(&mut rom, TIMER_VECTOR, &play);
+
+        // Entry point, jumping over the header to the driver
+        write_code(&mut rom, 0x100, &[&[0x00][..], &jp(DRIVER_ADDR)].concat());
+        write_code(&mut rom, 0x104, &[0; DRIVER_ADDR as usize - 0x104]);
+
+        let (tma, tac, ie) = if h.uses_timer() {
+            let (tma, tac) = h.timer_registers();
+            (tma, tac, 0x04)
+        } else {
+            (0, 0, 0x01)
+        };
+        let [sp_lo, sp_hi] = h.stack_pointer.to_le_bytes();
+        let driver = [
+            &[0xf3][..],              // di
+            &[0x31, sp_lo, sp_hi],    // ld sp, stack_pointer
+            &[0x3e, song - 1],        // ld a, song
+            &call(h.init_addr),       // call init
+            &[0x3e, tma, 0xe0, 0x06], // ld a, tma; ldh (TMA), a
+            &[0x3e, tac, 0xe0, 0x07], // ld a, tac; ldh (TAC), a
+            &[0x3e, ie, 0xe0, 0xff],  // ld a, ie; ldh (IE), a
+            &[0xaf, 0xe0, 0x0f],      // xor a; ldh (IF), a
+            &[0xfb],                  // ei
+            &[0x76, 0x18, 0xfd],      // loop: halt; jr loop
+        ]
+        .concat();
+        write_code(&mut rom, DRIVER_ADDR, &driver);
+
+        Ok(rom)
+    }
+}
+
+fn jp(addr: u16) -> [u8; 3] {
+    let [lo, hi] = addr.to_le_bytes();
+    [0xc3, lo, hi]
+}
+
+fn call(addr: u16) -> [u8; 3] {
+    let [lo, hi] = addr.to_le_bytes();
+    [0xcd, lo, hi]
+}
+
+fn write_code(rom: &mut [u8], addr: u16, code: &[u8]) {
+    rom[addr as usize..addr as usize + code.len()].copy_from_slice(code);
+}
+
+/// Whether a path looks like a GBS file rather than a ROM
+pub fn is_gbs_path<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref()
+        .extension()
+        .map_or(false, |ext| ext.eq_ignore_ascii_case("gbs"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn make_file(load_addr: u16, timer_control: u8) -> Vec<u8> {
+        let mut file = vec![0; HEADER_LEN + 0x10];
+        file[0..4].copy_from_slice(b"GBS\x01");
+        file[4] = 3;
+        file[5] = 1;
+        file[0x06..0x08].copy_from_slice(&load_addr.to_le_bytes());
+        file[0x08..0x0a].copy_from_slice(&(load_addr + 4).to_le_bytes());
+        file[0x0a..0x0c].copy_from_slice(&(load_addr + 8).to_le_bytes());
+        file[0x0c..0x0e].copy_from_slice(&0xdffeu16.to_le_bytes());
+        file[0x0e] = 0xc0;
+        file[0x0f] = timer_control;
+        file[0x10..0x14].copy_from_slice(b"Test");
+        file
+    }
+
+    #[test]
+    fn builds_driver() {
+        let file = make_file(0x400, 0x84);
+        let header = GbsHeader::parse(&file).unwrap();
+        assert_eq!(header.title, "Test");
+        assert!(header.uses_timer());
+        let gbs = Gbs {
+            header,
+            data: file[HEADER_LEN..].to_vec(),
+        };
+
+        let rom = gbs.rom(2).unwrap();
+        assert_eq!(rom.len(), 0x8000);
+        assert_eq!(&rom[0x08..0x0b], &[0xc3, 0x08, 0x04]);
+        assert_eq!(&rom[0x50..0x54], &[0xcd, 0x08, 0x04, 0xd9]);
+        assert_eq!(&rom[0x150..0x156], &[0xf3, 0x31, 0xfe, 0xdf, 0x3e, 0x01]);
+        // Double speed halves the 0x40 ticks between overflows instead of setting the CGB bit
+        assert_eq!(&rom[0x159..0x15d], &[0x3e, 0xe0, 0xe0, 0x06]);
+        assert_eq!(&rom[0x15d..0x161], &[0x3e, 0x04, 0xe0, 0x07]);
+        assert!(gbs.rom(4).is_err());
+    }
+
+    #[test]
+    fn rejects_low_load_address() {
+        let file = make_file(0x100, 0);
+        assert!(matches!(
+            GbsHeader::parse(&file),
+            Err(Error::BadLoadAddress(0x100))
+        ));
+    }
+}
@@ -1,4 +1,4 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
 use anyhow::Error;
@@ -7,14 +7,23 @@ use log::*;
 use crate::compiler::{CycleState, ExternalBus};
 use crate::cpu_state::CpuState;
 use crate::executor::{Executor, ExecutorOptions};
+use crate::Args;
 
 pub mod bus;
 pub mod devices;
 mod event_manager;
+pub mod gbs;
+mod post_boot;
+pub mod state;
 
-use bus::{Bus, DeviceWrapper, PageId, PageStatus};
-use devices::{Frame, Ppu};
+use bus::{Bus, DeviceWrapper, Kind, Module, PageId, PageStatus};
+use devices::{Apu, Button, Dma, Frame, IntController, Joypad, Ppu, Sample, Timer, DMA_LEN};
 use event_manager::{EventCycle, EventManager, EventSource};
+use gbs::Gbs;
+use state::{Reader, Snapshot};
+
+/// How often battery-backed RAM is flushed to disk, so a crash loses at most a few seconds
+const SAVE_INTERVAL_FRAMES: u64 = 5 * 60;
 
 pub struct Gb {
     cycles: Rc<CycleState>,
@@ -23,12 +32,37 @@ pub struct Gb {
 
     event_manager: EventManager,
     executor: Executor<PageId, Components>,
+
+    frames: u64,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct GbOptions {
+    pub rtc_host_clock: bool,
+    /// Overrides where battery-backed RAM is saved, instead of next to the ROM
+    pub save_path: Option<PathBuf>,
+    /// Render with the slower pixel FIFO, for games that change registers partway through a line
+    pub pixel_fifo: bool,
+    /// Neither load nor write battery-backed RAM's save file, for movies that bring their own
+    pub no_save_file: bool,
+}
+
+/// What the system put out over a frame
+pub struct FrameOutput {
+    pub frame: Box<Frame>,
+    /// Audio produced while the frame was drawn, one sample every `apu::CYCLES_PER_SAMPLE` cycles
+    pub samples: Vec<Sample>,
 }
 
 struct Components {
     cycles: Rc<CycleState>,
     bus: Bus,
     ppu: Ppu,
+    int_controller: IntController,
+    timer: Timer,
+    joypad: Joypad,
+    dma: Dma,
+    apu: Apu,
     execution_state: Option<ExecutionState>,
 }
 
@@ -40,69 +74,287 @@ struct ExecutionState {
 }
 
 impl Gb {
+    /// Without a BIOS, execution starts at 0x0100 with the system in the state the boot ROM
+    /// would have left it in.
     pub fn new<P: AsRef<Path>, R: AsRef<Path>>(
-        bios_path: P,
+        bios_path: Option<P>,
         cartridge_path: R,
-        options: ExecutorOptions,
+        options: GbOptions,
+        executor_options: ExecutorOptions,
     ) -> Result<Self, Error> {
+        let skip_bios = bios_path.is_none();
         let cycles = Rc::new(CycleState::new());
-        let cpu_state = CpuState::new();
-        let bus = Bus::new(bios_path, cartridge_path)?;
-        let (ppu, ppu_cycle) = Ppu::new(cycles.clone());
+        let bus = Bus::new(bios_path, cartridge_path, cycles.clone(), &options)?;
+        Gb::with_bus(cycles, bus, skip_bios, options, executor_options)
+    }
+
+    /// Play a song from a GBS file, numbered from 1, or the file's default song if not given.
+    /// There's no BIOS, the player starts from the state the boot ROM would have left.
+    pub fn new_gbs<P: AsRef<Path>>(
+        path: P,
+        song: Option<u8>,
+        options: GbOptions,
+        executor_options: ExecutorOptions,
+    ) -> Result<Self, Error> {
+        let gbs = Gbs::load(path)?;
+        let h = &gbs.header;
+        let song = song.unwrap_or(h.first_song);
+        info!(
+            "Playing song {} of {} from {:?} by {:?}, {:?}",
+            song, h.songs, h.title, h.author, h.copyright
+        );
+        let bus = Bus::new_gbs(gbs.rom(song)?, &h.title)?;
+        let cycles = Rc::new(CycleState::new());
+        Gb::with_bus(cycles, bus, true, options, executor_options)
+    }
+
+    fn with_bus(
+        cycles: Rc<CycleState>,
+        bus: Bus,
+        skip_bios: bool,
+        options: GbOptions,
+        executor_options: ExecutorOptions,
+    ) -> Result<Self, Error> {
+        if skip_bios {
+            // Start as late as the boot ROM would have finished, so that devices can be put
+            // partway through their timelines
+            cycles.advance(post_boot::CYCLES);
+        }
+        let cpu_state = if skip_bios {
+            post_boot::cpu_state()
+        } else {
+            CpuState::new()
+        };
+        let (ppu, ppu_cycle) = if skip_bios {
+            Ppu::new_post_boot(cycles.clone(), options.pixel_fifo)
+        } else {
+            Ppu::new(cycles.clone(), options.pixel_fifo)
+        };
+        let int_controller = IntController::new(cycles.clone());
+        let mut timer = Timer::new(cycles.clone());
+        if skip_bios {
+            timer.set_counter(post_boot::CYCLES);
+        }
+        let joypad = Joypad::new();
+        let dma = Dma::new(cycles.clone());
+        let mut apu = Apu::new(cycles.clone());
         let mut event_manager = EventManager::new(cycles.clone());
         let executor = Executor::new(
             ExternalBus {
                 read: Components::read,
                 write: Components::write,
             },
-            options,
+            executor_options,
         )?;
         let execution_state = None;
 
         event_manager.add_event(EventSource::Ppu, ppu_cycle);
+        event_manager.add_event(EventSource::Apu, apu.process());
 
-        Ok(Gb {
+        let mut gb = Gb {
             cycles: cycles.clone(),
             cpu_state,
             components: Components {
                 cycles,
                 bus,
                 ppu,
+                int_controller,
+                timer,
+                joypad,
+                dma,
+                apu,
                 execution_state,
             },
             event_manager,
             executor,
-        })
+            frames: 0,
+        };
+        if skip_bios {
+            for &(addr, val) in post_boot::IO_REGISTERS {
+                gb.components.do_write(addr, val);
+            }
+        }
+        Ok(gb)
     }
 
-    pub fn run_frame(&mut self) -> Result<Box<Frame>, Error> {
+    pub fn run_frame(&mut self) -> Result<FrameOutput, Error> {
         self.event_manager
             .add_event(EventSource::FrameEnd, self.components.ppu.next_frame_end());
 
         let mut frame_ended = false;
         while !frame_ended {
             self.cpu_exec()?;
+            self.reschedule_devices();
             for source in self.event_manager.get_events() {
                 use EventSource::*;
                 match source {
                     Ppu => {
-                        let next = self.components.ppu.process(&mut self.components.bus);
+                        let c = &mut self.components;
+                        let next = c.ppu.process(&mut c.bus, &mut c.int_controller);
                         self.event_manager.add_event(Ppu, next);
                     }
+                    Timer => {
+                        let c = &mut self.components;
+                        if let Some(next) = c.timer.process(&mut c.int_controller) {
+                            self.event_manager.add_event(Timer, next);
+                        }
+                    }
+                    Dma => self.components.finish_dma(),
+                    Apu => {
+                        let next = self.components.apu.process();
+                        self.event_manager.add_event(Apu, next);
+                    }
                     FrameEnd => frame_ended = true,
                 }
             }
         }
 
-        Ok(self
-            .components
-            .ppu
-            .take_frame()
-            .expect("Frame should be complete"))
+        self.frames += 1;
+        if self.frames % SAVE_INTERVAL_FRAMES == 0 {
+            if let Err(err) = self.save() {
+                error!("Failed to write save file: {}", err);
+            }
+        }
+
+        let c = &mut self.components;
+        Ok(FrameOutput {
+            frame: c.ppu.take_frame().expect("Frame should be complete"),
+            samples: c.apu.take_samples(),
+        })
+    }
+
+    /// Update the state of one of the joypad buttons, takes effect the next time the cpu runs
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        let c = &mut self.components;
+        c.joypad.set_button(button, pressed, &mut c.int_controller);
+    }
+
+    /// The state of every button, with each `Button` as the bit at its position in the enum
+    pub fn buttons(&self) -> u8 {
+        self.components.joypad.pressed()
+    }
+
+    /// Set every button at once from a mask like the one `buttons` returns
+    pub fn set_buttons(&mut self, pressed: u8) {
+        let c = &mut self.components;
+        c.joypad.set_pressed(pressed, &mut c.int_controller);
+    }
+
+    /// Checksum of the cartridge's ROM, which identifies it in save states and movies
+    pub fn rom_checksum(&self) -> u64 {
+        self.components.bus.rom_checksum()
+    }
+
+    /// Whether lines are drawn with the pixel FIFO, which changes the timing of the machine
+    pub fn pixel_fifo(&self) -> bool {
+        self.components.ppu.pixel_fifo()
+    }
+
+    pub fn cartridge_header(&self) -> &bus::CartridgeHeader {
+        self.components.bus.cartridge_header()
+    }
+
+    /// Register a function to be called whenever the cartridge's rumble motor turns on or off
+    pub fn set_rumble_callback<F: FnMut(bool) + 'static>(&mut self, callback: F) {
+        self.components.bus.set_rumble_callback(Box::new(callback))
+    }
+
+    /// Write battery-backed cartridge RAM to the save file, if it has changed since the last save
+    pub fn save(&mut self) -> Result<(), Error> {
+        Ok(self.components.bus.save_cartridge_ram()?)
+    }
+
+    /// Snapshot the whole machine, to be restored with `load_state`.  The state is taken between
+    /// frames, and doesn't include battery-backed RAM's save file or any host settings.
+    pub fn save_state(&self) -> Vec<u8> {
+        self.save_state_pages().0
+    }
+
+    /// `save_state`, along with where each page of RAM is in it and which version it's at
+    pub fn save_state_pages(&self) -> (Vec<u8>, Vec<state::Page>) {
+        let c = &self.components;
+        let mut out = Vec::new();
+        let mut pages = Vec::new();
+        state::write_header(&mut out, c.bus.rom_checksum());
+        self.cycles.cycle().save_state(&mut out);
+        self.frames.save_state(&mut out);
+        self.cpu_state.save_state(&mut out);
+        c.bus.save_state_pages(&mut out, &mut pages);
+        c.ppu.save_state(&mut out);
+        c.int_controller.save_state(&mut out);
+        c.timer.save_state(&mut out);
+        c.joypad.save_state(&mut out);
+        c.dma.save_state(&mut out);
+        c.apu.save_state(&mut out);
+        self.event_manager.save_state(&mut out);
+        (out, pages)
+    }
+
+    /// Put the machine back into a state from `save_state`, made with the same cartridge.  On
+    /// failure the machine is left as it was.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), Error> {
+        let backup = self.save_state();
+        if let Err(err) = self.restore(data) {
+            self.restore(&backup)
+                .expect("Restoring the state from before a failed load should succeed");
+            return Err(err.into());
+        }
+        // Memory has been replaced wholesale, so start over rather than checking every page
+        self.executor.clear();
+        Ok(())
+    }
+
+    fn restore(&mut self, data: &[u8]) -> Result<(), state::Error> {
+        let mut r = Reader::new(data);
+        state::read_header(&mut r, self.components.bus.rom_checksum())?;
+        let mut cycle = 0u64;
+        cycle.load_state(&mut r)?;
+        self.cycles.set_cycle(cycle);
+        self.frames.load_state(&mut r)?;
+        self.cpu_state.load_state(&mut r)?;
+        let c = &mut self.components;
+        c.bus.load_state(&mut r)?;
+        c.ppu.load_state(&mut r)?;
+        c.int_controller.load_state(&mut r)?;
+        c.timer.load_state(&mut r)?;
+        c.joypad.load_state(&mut r)?;
+        c.dma.load_state(&mut r)?;
+        c.apu.load_state(&mut r)?;
+        self.event_manager.load_state(&mut r)?;
+        r.finish()
+    }
+
+    /// Devices whose register writes can move their next event flag it, pick those up here
+    fn reschedule_devices(&mut self) {
+        if let Some(next) = self.components.timer.take_reschedule() {
+            self.event_manager.remove_events(EventSource::Timer);
+            if let Some(next) = next {
+                self.event_manager.add_event(EventSource::Timer, next);
+            }
+        }
+        let c = &mut self.components;
+        if let Some(next) = c.ppu.take_reschedule(&mut c.bus, &mut c.int_controller) {
+            self.event_manager.remove_events(EventSource::Ppu);
+            self.event_manager.add_event(EventSource::Ppu, next);
+        }
+        if let Some(next) = self.components.dma.take_reschedule() {
+            self.event_manager.remove_events(EventSource::Dma);
+            self.event_manager.add_event(EventSource::Dma, next);
+        }
     }
 
     fn cpu_exec(&mut self) -> Result<(), Error> {
-        // TODO: Allow for halted cpu
+        if self.cpu_state.halted {
+            if !self.components.int_controller.pending() {
+                self.skip_to_next_event();
+                return Ok(());
+            }
+            trace!("Waking from halt at {:#06x?}", self.cpu_state.pc);
+            self.cpu_state.halted = false;
+        }
+
+        self.dispatch_interrupt();
+
         let (page, data) = self.components.map_page(self.cpu_state.pc);
         let code = self
             .executor
@@ -116,13 +368,118 @@ impl Gb {
         trace!("Entering code block");
         code.enter(&mut self.cpu_state, &mut self.components, &self.cycles);
         self.components.execution_state.take();
+
+        if self.components.bus.take_bios_unmapped() {
+            // The write that unmapped it remapped the page being executed, so the block has
+            // already stopped and the next one will be compiled from the cartridge
+            self.executor.evict(&(Kind::Bios, 0));
+        }
+
+        if self.cpu_state.halted
+            && !self.cpu_state.intenable
+            && self.components.int_controller.pending()
+        {
+            self.cpu_state.halted = false;
+            self.exec_halt_bug()?;
+        }
         Ok(())
     }
+
+    /// Nothing can wake a halted cpu until an event fires, so skip straight to the next one.
+    fn skip_to_next_event(&mut self) {
+        let current = self.cycles.cycle();
+        let next = self.event_manager.next_event().unwrap_or(current);
+        if next > current {
+            self.cycles.advance(next - current);
+        }
+    }
+
+    /// HALT executed with interrupts disabled but one already pending doesn't halt, but the pc
+    /// fails to increment after the next opcode fetch, so that byte is read twice.  Emulate this
+    /// by running a single instruction compiled from the bytes as the cpu sees them, starting one
+    /// address early.
+    fn exec_halt_bug(&mut self) -> Result<(), Error> {
+        let pc = self.cpu_state.pc;
+        debug!("Executing halt bug at {:#06x?}", pc);
+
+        let op = self.components.read(pc);
+        let data = [op, op, self.components.read(pc.wrapping_add(1))];
+        let base_addr = pc.wrapping_sub(1);
+        let code = self.executor.compile_uncached(base_addr, &data)?;
+
+        let (page, _) = self.components.map_page(pc);
+        self.components.execution_state = Some(ExecutionState {
+            pc,
+            id: page.id,
+            version: page.version,
+        });
+        self.cpu_state.pc = base_addr;
+        self.cycles.force_stop();
+        code.enter(&mut self.cpu_state, &mut self.components, &self.cycles);
+        self.components.execution_state.take();
+        self.event_manager.update_limit();
+        Ok(())
+    }
+
+    /// If interrupts are enabled and one is pending, push the pc and jump to its vector so that
+    /// the next code block entered is the handler.
+    fn dispatch_interrupt(&mut self) {
+        if !self.cpu_state.intenable {
+            return;
+        }
+        let int = match self.components.int_controller.acknowledge() {
+            Some(int) => int,
+            None => return,
+        };
+        debug!(
+            "Dispatching interrupt {:?} at pc {:#06x?}",
+            int, self.cpu_state.pc
+        );
+
+        let [lo, hi] = self.cpu_state.pc.to_le_bytes();
+        self.cpu_state.sp = self.cpu_state.sp.wrapping_sub(1);
+        self.components.do_write(self.cpu_state.sp, hi);
+        self.cpu_state.sp = self.cpu_state.sp.wrapping_sub(1);
+        self.components.do_write(self.cpu_state.sp, lo);
+
+        self.cpu_state.intenable = false;
+        self.cpu_state.pc = int.vector();
+        self.cycles.advance(20);
+    }
+}
+
+impl Drop for Gb {
+    fn drop(&mut self) {
+        if let Err(err) = self.save() {
+            error!("Failed to write save file: {}", err);
+        }
+    }
+}
+
+impl GbOptions {
+    pub fn new(args: &Args) -> Self {
+        GbOptions {
+            rtc_host_clock: args.rtc_host_clock,
+            save_path: args.save_path.as_ref().map(PathBuf::from),
+            pixel_fifo: args.pixel_fifo,
+            no_save_file: args.play_movie.is_some(),
+        }
+    }
 }
 
 impl Components {
     fn device_wrapper(&mut self) -> (DeviceWrapper<'_>, &mut Bus) {
-        (DeviceWrapper::new(&mut self.ppu), &mut self.bus)
+        (
+            DeviceWrapper::new(
+                &mut self.ppu,
+                &mut self.int_controller,
+                &mut self.timer,
+                &mut self.joypad,
+                &mut self.dma,
+                &mut self.apu,
+            ),
+            &mut self.bus,
+        )
     }
 
     fn read(&mut self, addr: u16) -> u8 {
@@ -138,6 +495,15 @@ impl Components {
         bus.write(&mut devices, addr, val)
     }
 
+    /// Copy the source of a finished DMA transfer into OAM
+    fn finish_dma(&mut self) {
+        let source = self.dma.process();
+        for i in 0..DMA_LEN {
+            let val = self.bus.dma_read(source + i);
+            self.bus.oam.write(0xfe00 + i, val);
+        }
+    }
+
     fn map_page(&mut self, addr: u16) -> (PageStatus, &[u8]) {
         let (mut devices, bus) = self.device_wrapper();
         bus.map_page(&mut devices, addr)
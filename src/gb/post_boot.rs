@@ -0,0 +1,51 @@
+//! The state the DMG boot ROM leaves the system in when it hands control to the cartridge, for
+//! starting without a BIOS file.
+
+use crate::cpu_state::CpuState;
+
+/// Cycle count at the handoff, which puts the system counter (and so DIV) where the boot ROM
+/// leaves it.  This is also long enough that the PPU can be partway through a frame.
+pub const CYCLES: u64 = 0x1_abcc;
+
+/// IO register values at the handoff, written through the bus so they end up in whichever device
+/// owns them.  Registers that the boot ROM leaves at their reset value are omitted.
+pub const IO_REGISTERS: &[(u16, u8)] = &[
+    (0xff00, 0xcf),
+    (0xff02, 0x7e),
+    (0xff0f, 0xe1),
+    // The APU has to be on for the other sound registers to be written
+    (0xff26, 0xf1),
+    (0xff10, 0x80),
+    (0xff11, 0xbf),
+    // Channel 1 is still on after the startup sound, but has faded out.  Trigger it with a silent
+    // envelope before setting the one it played with.
+    (0xff12, 0x08),
+    (0xff14, 0xbf),
+    (0xff12, 0xf3),
+    (0xff16, 0x3f),
+    (0xff19, 0xbf),
+    (0xff1a, 0x7f),
+    (0xff1b, 0xff),
+    (0xff1c, 0x9f),
+    (0xff1e, 0xbf),
+    (0xff20, 0xff),
+    (0xff23, 0xbf),
+    (0xff24, 0x77),
+    (0xff25, 0xf3),
+    (0xff40, 0x91),
+    (0xff47, 0xfc),
+];
+
+pub fn cpu_state() -> CpuState {
+    CpuState {
+        sp: 0xfffe,
+        pc: 0x0100,
+        // A = 0x01, with Z, H and C set.  Flags are stored in the LAHF layout.
+        af: 0x5101,
+        bc: 0x0013,
+        de: 0x00d8,
+        hl: 0x014d,
+        intenable: false,
+        halted: false,
+    }
+}
@@ -0,0 +1,296 @@
+//! Save states, snapshots of the whole machine that it can be put back into.  The format is a
+//! magic number and version followed by each component's state in a fixed order, with no field
+//! names or lengths beyond those of variable-sized data, so any change to what a component saves
+//! needs a new version.
+
+use quick_error::quick_error;
+
+use crate::cpu_state::CpuState;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        BadMagic {
+            display("Not a save state")
+        }
+        UnsupportedVersion(version: u32) {
+            display("Unsupported save state version {}, expected {}", version, VERSION)
+        }
+        WrongCartridge {
+            display("Save state is for a different cartridge")
+        }
+        WrongRenderer(pixel_fifo: bool) {
+            display("Save state was made {} --pixel-fifo, start the same way to load it",
+                if *pixel_fifo { "with" } else { "without" })
+        }
+        NeedsBios {
+            display("Save state was made while the BIOS was running, start with a BIOS to load it")
+        }
+        Truncated {
+            display("Save state ends early")
+        }
+        Invalid(what: &'static str) {
+            display("Save state has an invalid {}", what)
+        }
+    }
+}
+
+const MAGIC: &[u8; 8] = b"GBJITSST";
+
+/// Bumped whenever the layout of any component's state changes
+pub const VERSION: u32 = 3;
+
+/// Where a page of memory is in a save state, and its version when the state was made.  Versions
+/// only go up, so a page with the same version in two states has the same contents in both.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Page {
+    pub offset: usize,
+    pub len: usize,
+    pub version: u64,
+}
+
+/// State that can be written out and read back in place.  Anything that's configuration rather
+/// than state, like which hardware a cartridge has, is left as it is on load.
+pub trait Snapshot {
+    fn save_state(&self, out: &mut Vec<u8>);
+    fn load_state(&mut self, r: &mut Reader<'_>) -> Result<(), Error>;
+}
+
+pub struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Reader { data }
+    }
+
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if len > self.data.len() {
+            return Err(Error::Truncated);
+        }
+        let (head, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(head)
+    }
+
+    /// Check that everything has been read, leftovers mean the state doesn't match the machine
+    pub fn finish(&self) -> Result<(), Error> {
+        if self.data.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Invalid("length"))
+        }
+    }
+}
+
+/// Start a save state for a cartridge, identified by a checksum of its ROM
+pub fn write_header(out: &mut Vec<u8>, rom_checksum: u64) {
+    out.extend_from_slice(MAGIC);
+    VERSION.save_state(out);
+    rom_checksum.save_state(out);
+}
+
+pub fn read_header(r: &mut Reader<'_>, rom_checksum: u64) -> Result<(), Error> {
+    if r.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+        return Err(Error::BadMagic);
+    }
+    let mut version = 0u32;
+    version.load_state(r)?;
+    if version != VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+    let mut checksum = 0u64;
+    checksum.load_state(r)?;
+    if checksum != rom_checksum {
+        return Err(Error::WrongCartridge);
+    }
+    Ok(())
+}
+
+/// 64 bit FNV-1a, to tell ROMs apart
+pub fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100_0000_01b3)
+    })
+}
+
+/// Implement `Snapshot` for a struct by saving and loading the listed fields in order
+macro_rules! impl_snapshot {
+    ($t:ty { $($field:ident),* $(,)? }) => {
+        impl $crate::gb::state::Snapshot for $t {
+            fn save_state(&self, _out: &mut Vec<u8>) {
+                $( $crate::gb::state::Snapshot::save_state(&self.$field, _out); )*
+            }
+
+            fn load_state(
+                &mut self,
+                _r: &mut $crate::gb::state::Reader<'_>,
+            ) -> Result<(), $crate::gb::state::Error> {
+                $( $crate::gb::state::Snapshot::load_state(&mut self.$field, _r)?; )*
+                Ok(())
+            }
+        }
+    };
+}
+
+pub(crate) use impl_snapshot;
+
+macro_rules! impl_snapshot_int {
+    ($($t:ty),*) => {
+        $(
+            impl Snapshot for $t {
+                fn save_state(&self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&self.to_le_bytes());
+                }
+
+                fn load_state(&mut self, r: &mut Reader<'_>) -> Result<(), Error> {
+                    let mut bytes = [0; std::mem::size_of::<$t>()];
+                    let len = bytes.len();
+                    bytes.copy_from_slice(r.take(len)?);
+                    *self = <$t>::from_le_bytes(bytes);
+                    Ok(())
+                }
+            }
+        )*
+    };
+}
+
+impl_snapshot_int!(u8, u16, u32, u64);
+
+impl Snapshot for bool {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        (*self as u8).save_state(out)
+    }
+
+    fn load_state(&mut self, r: &mut Reader<'_>) -> Result<(), Error> {
+        *self = match r.take(1)?[0] {
+            0 => false,
+            1 => true,
+            _ => return Err(Error::Invalid("flag")),
+        };
+        Ok(())
+    }
+}
+
+impl Snapshot for f32 {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        self.to_bits().save_state(out)
+    }
+
+    fn load_state(&mut self, r: &mut Reader<'_>) -> Result<(), Error> {
+        let mut bits = 0u32;
+        bits.load_state(r)?;
+        *self = f32::from_bits(bits);
+        Ok(())
+    }
+}
+
+impl<A: Snapshot, B: Snapshot> Snapshot for (A, B) {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        self.0.save_state(out);
+        self.1.save_state(out);
+    }
+
+    fn load_state(&mut self, r: &mut Reader<'_>) -> Result<(), Error> {
+        self.0.load_state(r)?;
+        self.1.load_state(r)
+    }
+}
+
+impl<T: Snapshot + Default> Snapshot for Option<T> {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        self.is_some().save_state(out);
+        if let Some(val) = self {
+            val.save_state(out);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut Reader<'_>) -> Result<(), Error> {
+        let mut present = false;
+        present.load_state(r)?;
+        if present {
+            self.get_or_insert_with(Default::default).load_state(r)
+        } else {
+            *self = None;
+            Ok(())
+        }
+    }
+}
+
+impl<T: Snapshot + Default> Snapshot for Vec<T> {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        (self.len() as u32).save_state(out);
+        for val in self {
+            val.save_state(out);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut Reader<'_>) -> Result<(), Error> {
+        let mut len = 0u32;
+        len.load_state(r)?;
+        self.clear();
+        for _ in 0..len {
+            let mut val = T::default();
+            val.load_state(r)?;
+            self.push(val);
+        }
+        Ok(())
+    }
+}
+
+impl<T: Snapshot, const N: usize> Snapshot for [T; N] {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        for val in self {
+            val.save_state(out);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut Reader<'_>) -> Result<(), Error> {
+        for val in self {
+            val.load_state(r)?;
+        }
+        Ok(())
+    }
+}
+
+impl_snapshot!(CpuState {
+    sp,
+    pc,
+    af,
+    bc,
+    de,
+    hl,
+    intenable,
+    halted,
+});
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trips_fields() {
+        let mut out = Vec::new();
+        write_header(&mut out, 0x1234);
+        (0xabcdu16, Some(true)).save_state(&mut out);
+        vec![1.5f32, -2.0].save_state(&mut out);
+
+        let mut r = Reader::new(&out);
+        read_header(&mut r, 0x1234).unwrap();
+        let mut pair = (0u16, None);
+        let mut floats: Vec<f32> = Vec::new();
+        pair.load_state(&mut r).unwrap();
+        floats.load_state(&mut r).unwrap();
+        r.finish().unwrap();
+        assert_eq!(pair, (0xabcd, Some(true)));
+        assert_eq!(floats, vec![1.5, -2.0]);
+
+        let mut r = Reader::new(&out);
+        assert!(matches!(read_header(&mut r, 1), Err(Error::WrongCartridge)));
+        let mut r = Reader::new(&out[..out.len() - 1]);
+        read_header(&mut r, 0x1234).unwrap();
+        assert!(pair.load_state(&mut r).is_ok());
+        assert!(matches!(floats.load_state(&mut r), Err(Error::Truncated)));
+    }
+}
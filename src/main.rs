@@ -21,6 +21,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let args = Args::from_args();
 
+    if args.track.is_some() && !gb::gbs::is_gbs_path(&args.rom) {
+        return Err("--track only applies to GBS files".into());
+    }
+
     if args.headless {
         frontend::headless::run(args)
     } else {